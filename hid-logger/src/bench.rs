@@ -0,0 +1,449 @@
+// Times snippet typing end to end: a test snippet is stored over serial,
+// typed with RUN: and measured from the keyboard reports it produces
+
+use crate::descriptor::{usage, Value};
+use crate::{is_pad, read_descriptor, serial, PID, VID, SERIAL};
+use hidapi::HidApi;
+use macropad_core::layout::HostLayout;
+use macropad_core::scheduler::Pacing;
+use serialport::SerialPort;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Covers letters, digits, shifted chars and repeated letters, and fits in
+// a snippet slot
+const DEFAULT_TEXT: &str = "Pack my box with five dozen liquor jugs 0123456789! Bookkeeper.";
+
+// A run is over once no report has arrived for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+// Time to switch to a window the pad can safely type into
+const COUNTDOWN: u64 = 3;
+
+pub struct Options {
+    pub key: u8,
+    pub runs: usize,
+    pub text: String,
+    // Typing speed to bench at, as for SPD:, e.g. "1:1:NKRO"
+    pub speed: Option<String>,
+}
+
+impl Options {
+    pub fn parse(args: &[&str]) -> Option<Self> {
+        let mut options = Options {
+            key: 1,
+            runs: 5,
+            text: DEFAULT_TEXT.to_string(),
+            speed: None,
+        };
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            let value = *args.next()?;
+            match arg {
+                "--key" => options.key = value.parse().ok().filter(|k| (1..=11).contains(k))?,
+                "--runs" => options.runs = value.parse().ok().filter(|&n| n > 0)?,
+                "--text" => options.text = value.to_string(),
+                "--speed" => {
+                    Pacing::parse(value.as_bytes(), b':')?;
+                    options.speed = Some(value.to_string());
+                }
+                _ => return None,
+            }
+        }
+        Some(options)
+    }
+}
+
+// One char's key press as seen in a report
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Stroke {
+    key: u8,
+    shift: bool,
+    altgr: bool,
+}
+
+// Strokes the firmware sends to type `text` on a host with `layout`. Chars
+// the layout can't type are skipped, as the firmware skips them.
+fn expected_strokes(text: &str, layout: HostLayout) -> Vec<Stroke> {
+    let mut strokes = Vec::new();
+    for c in text.chars() {
+        let Some(stroke) = layout.key_for(c) else {
+            continue;
+        };
+        strokes.push(Stroke {
+            key: stroke.key.into(),
+            shift: stroke.shift,
+            altgr: stroke.altgr,
+        });
+        // Dead keys are followed by Space
+        if stroke.dead {
+            strokes.push(Stroke { key: 0x2C, shift: false, altgr: false });
+        }
+    }
+    strokes
+}
+
+// Keys newly down in each report, with the modifiers held alongside them
+fn report_strokes(reports: &[Vec<Value>]) -> Vec<Vec<Stroke>> {
+    let mut down: Vec<u32> = Vec::new();
+    let mut strokes = Vec::new();
+    for values in reports {
+        let pressed: Vec<u32> = values
+            .iter()
+            .filter_map(|v| match *v {
+                Value::Pressed(u) if u >> 16 == 0x07 => Some(u),
+                _ => None,
+            })
+            .collect();
+        let shift = pressed.contains(&usage(0x07, 0xE1)) || pressed.contains(&usage(0x07, 0xE5));
+        let altgr = pressed.contains(&usage(0x07, 0xE6));
+        let new = pressed
+            .iter()
+            .filter(|&&u| u & 0xFFFF < 0xE0 && !down.contains(&u))
+            .map(|&u| Stroke { key: u as u8, shift, altgr })
+            .collect();
+        strokes.push(new);
+        down = pressed;
+    }
+    strokes
+}
+
+// Matches what arrived against what was expected, in order. Several keys
+// going down in one report may be matched in any order. Returns the number
+// of expected strokes seen and the number of strokes that weren't expected.
+fn align(expected: &[Stroke], observed: &[Vec<Stroke>]) -> (usize, usize) {
+    let (mut next, mut matched, mut extra) = (0, 0, 0);
+    for strokes in observed {
+        let mut strokes = strokes.clone();
+        while !strokes.is_empty() {
+            // Expected strokes skipped over here were dropped
+            let found = (next..expected.len()).find_map(|q| {
+                strokes.iter().position(|s| *s == expected[q]).map(|i| (q, i))
+            });
+            let Some((q, i)) = found else {
+                extra += strokes.len();
+                break;
+            };
+            strokes.remove(i);
+            matched += 1;
+            next = q + 1;
+        }
+    }
+    (matched, extra)
+}
+
+struct Run {
+    first_ms: f64,
+    total_ms: f64,
+    reports: usize,
+    dropped: usize,
+    extra: usize,
+    gaps_ms: Vec<f64>,
+}
+
+pub fn run(options: &Options) {
+    if options.text.contains(['{', '\n', '\r']) {
+        println!("Benchmark text must be plain text on one line, without escapes.");
+        return;
+    }
+
+    let api = HidApi::new().expect("Failed to create HID API");
+    let keyboard = api
+        .device_list()
+        .find(|d| is_pad(d) && d.usage_page() == 0x01 && d.usage() == 0x06)
+        .and_then(|d| d.open_device(&api).ok());
+    let Some(keyboard) = keyboard else {
+        println!("Could not open MacroPad keyboard interface.");
+        println!("Try running with sudo or check permissions.");
+        return;
+    };
+    let descriptor = read_descriptor(&keyboard, "keyboard");
+
+    let mut port = match serial::open(VID, PID, SERIAL) {
+        Ok((_, port)) => Port::new(port),
+        Err(e) => {
+            println!("Could not open MacroPad serial port: {}", e);
+            return;
+        }
+    };
+
+    // Read on a thread of its own so reports are timed as they arrive
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        loop {
+            match keyboard.read_timeout(&mut buf, 100) {
+                Ok(len) if len > 0 => {
+                    if tx.send((Instant::now(), descriptor.decode(&buf[..len]))).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Error reading keyboard: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    let key = options.key.to_string();
+    let setup = (|| {
+        let layout = port.query("LAY?")?;
+        let layout = HostLayout::from_name(layout.as_bytes())
+            .ok_or(format!("unknown host layout {}", layout))?;
+        // A macro in the slot would play instead of the text
+        if !port.query(&format!("MAC?:{}", key))?.is_empty() {
+            return Err(format!("key {} has a macro; pick another with --key", key));
+        }
+        let old = port.query(&format!("SNP?:{}", key))?;
+        let old_speed = port.query("SPD?")?;
+        port.command(&format!("SNP:{}:{}", key, options.text))?;
+        if let Some(speed) = &options.speed {
+            port.command(&format!("SPD:{}", speed))?;
+        }
+        Ok((layout, old, old_speed))
+    })();
+    let (layout, old_text, old_speed) = match setup {
+        Ok(setup) => setup,
+        Err(e) => {
+            println!("Setup failed: {}", e);
+            return;
+        }
+    };
+
+    let chars = options.text.chars().count();
+    let speed = options.speed.as_deref().unwrap_or(&old_speed);
+    println!(
+        "Typing {} chars from snippet {} at speed {}, {} times.",
+        chars, key, speed, options.runs
+    );
+    println!("Focus a window it's safe to type into.");
+    for n in (1..=COUNTDOWN).rev() {
+        println!("{}...", n);
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    let expected = expected_strokes(&options.text, layout);
+    let mut runs = Vec::new();
+    for n in 1..=options.runs {
+        match measure(&mut port, &rx, &key, &expected) {
+            Ok(run) => {
+                println!(
+                    "Run {}: first report {:.1} ms, done {:.1} ms, {} reports, {} dropped, {} extra",
+                    n, run.first_ms, run.total_ms, run.reports, run.dropped, run.extra
+                );
+                runs.push(run);
+            }
+            Err(e) => println!("Run {} failed: {}", n, e),
+        }
+    }
+
+    if let Err(e) = port.command(&format!("SNP:{}:{}", key, old_text)) {
+        println!("Could not restore snippet {}: {}", key, e);
+    }
+    if let Err(e) = port.command(&format!("SPD:{}", old_speed)) {
+        println!("Could not restore typing speed {}: {}", old_speed, e);
+    }
+    summarize(&runs, chars, expected.len());
+}
+
+fn measure(
+    port: &mut Port,
+    rx: &Receiver<(Instant, Vec<Value>)>,
+    key: &str,
+    expected: &[Stroke],
+) -> Result<Run, String> {
+    // Leftovers from the run before
+    while rx.try_recv().is_ok() {}
+
+    let sent = Instant::now();
+    port.command(&format!("RUN:{}", key))?;
+    let mut reports = Vec::new();
+    loop {
+        let timeout = if reports.is_empty() { REPLY_TIMEOUT } else { IDLE_TIMEOUT };
+        match rx.recv_timeout(timeout) {
+            Ok(report) => reports.push(report),
+            Err(mpsc::RecvTimeoutError::Timeout) => break,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Err("keyboard closed".into()),
+        }
+    }
+    let (Some(first), Some(last)) = (reports.first(), reports.last()) else {
+        return Err("no reports arrived".into());
+    };
+
+    let values: Vec<Vec<Value>> = reports.iter().map(|(_, v)| v.clone()).collect();
+    let (matched, extra) = align(expected, &report_strokes(&values));
+    Ok(Run {
+        first_ms: ms(first.0 - sent),
+        total_ms: ms(last.0 - sent),
+        reports: reports.len(),
+        dropped: expected.len() - matched,
+        extra,
+        gaps_ms: reports.windows(2).map(|w| ms(w[1].0 - w[0].0)).collect(),
+    })
+}
+
+fn summarize(runs: &[Run], chars: usize, strokes: usize) {
+    if runs.is_empty() {
+        return;
+    }
+    let totals: Vec<f64> = runs.iter().map(|r| r.total_ms).collect();
+    let firsts: Vec<f64> = runs.iter().map(|r| r.first_ms).collect();
+    let mut gaps: Vec<f64> = runs.iter().flat_map(|r| r.gaps_ms.iter().copied()).collect();
+    gaps.sort_by(f64::total_cmp);
+    let mean_total = mean(&totals);
+
+    println!("\nSummary over {} runs of {} chars ({} strokes):", runs.len(), chars, strokes);
+    println!(
+        "  First report: mean {:.1} ms, min {:.1}, max {:.1}",
+        mean(&firsts),
+        min(&firsts),
+        max(&firsts)
+    );
+    println!(
+        "  Whole string: mean {:.1} ms, min {:.1}, max {:.1}",
+        mean_total,
+        min(&totals),
+        max(&totals)
+    );
+    println!("  Throughput:   {:.1} chars/s", chars as f64 * 1000.0 / mean_total);
+    println!(
+        "  Report gaps:  min {:.2} ms, median {:.2}, p95 {:.2}, max {:.2}",
+        percentile(&gaps, 0.0),
+        percentile(&gaps, 0.5),
+        percentile(&gaps, 0.95),
+        percentile(&gaps, 1.0)
+    );
+    println!(
+        "  Dropped:      {} strokes, {} extra",
+        runs.iter().map(|r| r.dropped).sum::<usize>(),
+        runs.iter().map(|r| r.extra).sum::<usize>()
+    );
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn min(values: &[f64]) -> f64 {
+    values.iter().copied().fold(f64::INFINITY, f64::min)
+}
+
+fn max(values: &[f64]) -> f64 {
+    values.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+}
+
+// `sorted` must be sorted; 0.0 for no values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+// The serial port, for commands with sequence IDs
+struct Port {
+    reader: BufReader<Box<dyn SerialPort>>,
+    seq: u32,
+}
+
+impl Port {
+    fn new(port: Box<dyn SerialPort>) -> Self {
+        Self { reader: BufReader::new(port), seq: 0 }
+    }
+
+    // Sends a command and returns its data lines, or the pad's error
+    fn command(&mut self, cmd: &str) -> Result<Vec<String>, String> {
+        self.seq += 1;
+        let prefix = format!("#b{}:", self.seq);
+        let port = self.reader.get_mut();
+        port.write_all(format!("{}{}\n", prefix, cmd).as_bytes()).map_err(|e| e.to_string())?;
+
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let mut data = Vec::new();
+        let mut line = Vec::new();
+        while Instant::now() < deadline {
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(_) if line.ends_with(b"\n") => {}
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+            let text = String::from_utf8_lossy(&line).trim_end().to_string();
+            line.clear();
+            let Some(reply) = text.strip_prefix(&prefix) else {
+                continue;
+            };
+            if reply == "OK" {
+                return Ok(data);
+            }
+            if let Some(err) = reply.strip_prefix("ERR:") {
+                return Err(format!("{} failed: {}", cmd, err));
+            }
+            data.push(reply.to_string());
+        }
+        Err(format!("no reply to {}", cmd))
+    }
+
+    // A query's single value, e.g. "COLEMAK" from LAY:COLEMAK or "git push"
+    // from SNP:4:git push
+    fn query(&mut self, cmd: &str) -> Result<String, String> {
+        let data = self.command(cmd)?;
+        let line = data.first().ok_or(format!("no data for {}", cmd))?;
+        let fields = if cmd.contains(':') { 3 } else { 2 };
+        Ok(line.splitn(fields, ':').last().unwrap_or("").to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stroke(key: u8, shift: bool) -> Stroke {
+        Stroke { key, shift, altgr: false }
+    }
+
+    #[test]
+    fn expected_strokes_follow_the_host_layout() {
+        // Colemak's 'f' is QWERTY's E; '!' is Shift+1 on both
+        assert_eq!(
+            expected_strokes("f!", HostLayout::Colemak),
+            [stroke(0x08, false), stroke(0x1E, true)]
+        );
+    }
+
+    #[test]
+    fn repeated_letters_need_a_release() {
+        let a = vec![Value::Pressed(usage(0x07, 0x04))];
+        let reports = [a.clone(), a.clone(), vec![], a];
+        let strokes = report_strokes(&reports);
+        assert_eq!(strokes, [vec![stroke(0x04, false)], vec![], vec![], vec![stroke(0x04, false)]]);
+    }
+
+    #[test]
+    fn rolled_over_keys_count_once() {
+        let (a, b) = (Value::Pressed(usage(0x07, 0x04)), Value::Pressed(usage(0x07, 0x05)));
+        let reports = [vec![a], vec![a, b], vec![]];
+        let strokes = report_strokes(&reports);
+        assert_eq!(strokes, [vec![stroke(0x04, false)], vec![stroke(0x05, false)], vec![]]);
+    }
+
+    #[test]
+    fn drops_and_extras_are_counted() {
+        let expected = [stroke(4, false), stroke(5, false), stroke(6, false), stroke(7, false)];
+        // 5 is missing, 9 wasn't asked for, and 6 and 7 arrive together
+        let observed = [
+            vec![stroke(4, false)],
+            vec![stroke(9, false)],
+            vec![stroke(7, false), stroke(6, false)],
+        ];
+        assert_eq!(align(&expected, &observed), (3, 1));
+    }
+}
@@ -0,0 +1,237 @@
+// Captures are JSON Lines files, one report per line, so they can be
+// grepped, trimmed by hand and compared across firmware builds
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Report {
+    // Microseconds since the capture started
+    pub t_us: u64,
+    pub interface: String,
+    // Raw bytes as space-separated hex, or the line itself for serial
+    pub data: String,
+    pub decoded: String,
+}
+
+impl Report {
+    // Reports match when the same bytes arrived on the same interface,
+    // whenever they arrived
+    fn same_as(&self, other: &Report) -> bool {
+        self.interface == other.interface && self.data == other.data
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    hex.join(" ")
+}
+
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(&mut self, report: &Report) -> io::Result<()> {
+        serde_json::to_writer(&mut self.file, report)?;
+        self.file.write_all(b"\n")?;
+        // Flushed per report so Ctrl+C doesn't lose the tail of a capture
+        self.file.flush()
+    }
+}
+
+pub fn load(path: &str) -> io::Result<Vec<Report>> {
+    let mut reports = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let report = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path, number + 1, e))
+        })?;
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+pub fn print_timeline(reports: &[Report]) {
+    let mut last_us = 0;
+    for report in reports {
+        print_report(report, last_us);
+        last_us = report.t_us;
+    }
+}
+
+// Time since the start, time since the report before, interface, bytes and
+// decoded keys
+pub fn print_report(report: &Report, last_us: u64) {
+    println!(
+        "{:>10.3} ms (+{:>8.3}) {:<8} {}{}",
+        report.t_us as f64 / 1000.0,
+        report.t_us.saturating_sub(last_us) as f64 / 1000.0,
+        report.interface,
+        report.data,
+        decoded(report),
+    );
+}
+
+fn decoded(report: &Report) -> String {
+    match report.decoded.as_str() {
+        "" => String::new(),
+        decoded => format!(" | {}", decoded),
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Change<'a> {
+    Changed(&'a Report, &'a Report),
+    // In the first capture only
+    Missing(&'a Report),
+    // In the second capture only
+    Extra(&'a Report),
+}
+
+// Aligns two captures on their longest common run of matching reports and
+// lists what differs. A missing report directly followed by an extra one
+// is paired up as a change.
+pub fn diff<'a>(old: &'a [Report], new: &'a [Report]) -> Vec<Change<'a>> {
+    // Matching ends are skipped so the table below only covers the middle
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a.same_as(b)).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a.same_as(b))
+        .count();
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+
+    // lcs[i][j]: longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i].same_as(&new[j]) {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut missing, mut extra) = (Vec::new(), Vec::new());
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i].same_as(&new[j]) {
+            flush(&mut changes, &mut missing, &mut extra);
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            missing.push(&old[i]);
+            i += 1;
+        } else {
+            extra.push(&new[j]);
+            j += 1;
+        }
+    }
+    flush(&mut changes, &mut missing, &mut extra);
+    changes
+}
+
+fn flush<'a>(
+    changes: &mut Vec<Change<'a>>,
+    missing: &mut Vec<&'a Report>,
+    extra: &mut Vec<&'a Report>,
+) {
+    let paired = missing.len().min(extra.len());
+    for (old, new) in missing.iter().zip(extra.iter()) {
+        changes.push(Change::Changed(old, new));
+    }
+    changes.extend(missing.drain(..).skip(paired).map(Change::Missing));
+    changes.extend(extra.drain(..).skip(paired).map(Change::Extra));
+}
+
+pub fn print_diff(changes: &[Change]) {
+    let (mut changed, mut missing, mut extra) = (0, 0, 0);
+    for change in changes {
+        match change {
+            Change::Changed(old, new) => {
+                changed += 1;
+                print_change('~', old);
+                print_change(' ', new);
+            }
+            Change::Missing(old) => {
+                missing += 1;
+                print_change('-', old);
+            }
+            Change::Extra(new) => {
+                extra += 1;
+                print_change('+', new);
+            }
+        }
+    }
+    println!("\n{} changed, {} missing, {} extra", changed, missing, extra);
+}
+
+fn print_change(mark: char, report: &Report) {
+    println!(
+        "{} {:>10.3} ms {:<8} {}{}",
+        mark,
+        report.t_us as f64 / 1000.0,
+        report.interface,
+        report.data,
+        decoded(report),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(t_us: u64, data: &str) -> Report {
+        Report {
+            t_us,
+            interface: "keyboard".to_string(),
+            data: data.to_string(),
+            decoded: String::new(),
+        }
+    }
+
+    #[test]
+    fn timing_alone_is_not_a_difference() {
+        let old = [report(0, "01"), report(10, "00")];
+        let new = [report(5, "01"), report(90, "00")];
+        assert!(diff(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn changed_missing_and_extra() {
+        let old = [report(0, "01"), report(1, "02"), report(2, "00"), report(3, "03")];
+        let new = [
+            report(0, "01"),
+            report(1, "05"),
+            report(2, "00"),
+            report(3, "03"),
+            report(4, "00"),
+        ];
+        assert_eq!(
+            diff(&old, &new),
+            [Change::Changed(&old[1], &new[1]), Change::Extra(&new[4])]
+        );
+        assert_eq!(diff(&old[..2], &old[..1]), [Change::Missing(&old[1])]);
+    }
+
+    #[test]
+    fn reports_round_trip_through_json() {
+        let line = serde_json::to_string(&report(1500, "02 00 04")).unwrap();
+        assert_eq!(serde_json::from_str::<Report>(&line).unwrap(), report(1500, "02 00 04"));
+    }
+}
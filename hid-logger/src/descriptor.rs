@@ -0,0 +1,275 @@
+// Just enough of a HID report descriptor parser to find where each input
+// field sits in a report and which usages it carries
+
+// Usages are stored with their page in the high 16 bits
+pub fn usage(page: u16, id: u16) -> u32 {
+    (page as u32) << 16 | id as u32
+}
+
+#[derive(Debug)]
+pub struct Field {
+    pub report_id: u8,
+    // Bit position in the report, after the report ID byte if there is one
+    pub bit_offset: usize,
+    pub size: usize,
+    pub count: usize,
+    pub logical_min: i32,
+    // Variable fields have one usage per element; array fields hold indexes
+    // into `usages`
+    pub array: bool,
+    pub usages: Vec<u32>,
+}
+
+#[derive(Debug, Default)]
+pub struct Descriptor {
+    pub fields: Vec<Field>,
+    // Reports start with a report ID byte
+    pub numbered: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Globals {
+    usage_page: u16,
+    logical_min: i32,
+    report_size: usize,
+    report_count: usize,
+    report_id: u8,
+}
+
+pub fn parse(bytes: &[u8]) -> Descriptor {
+    let mut descriptor = Descriptor::default();
+    let mut globals = Globals::default();
+    let mut stack = Vec::new();
+    let mut usages = Vec::new();
+    let mut usage_min = None;
+    // Input bits used so far in each report
+    let mut offsets = [0usize; 256];
+
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let prefix = bytes[pos];
+        // Long items (never seen in practice) carry their size in the next byte
+        if prefix == 0xFE {
+            pos += 3 + *bytes.get(pos + 1).unwrap_or(&0) as usize;
+            continue;
+        }
+        let len = [0, 1, 2, 4][(prefix & 0x03) as usize];
+        let Some(data) = bytes.get(pos + 1..pos + 1 + len) else {
+            break;
+        };
+        pos += 1 + len;
+        let value = data.iter().rev().fold(0u32, |v, &b| v << 8 | b as u32);
+        let signed = match len {
+            1 => value as u8 as i8 as i32,
+            2 => value as u16 as i16 as i32,
+            _ => value as i32,
+        };
+        // Short usages are on the current page, 4-byte ones name their own
+        let full_usage = if len == 4 {
+            value
+        } else {
+            usage(globals.usage_page, value as u16)
+        };
+
+        match prefix & 0xFC {
+            // Input
+            0x80 => {
+                let constant = value & 0x01 != 0;
+                let array = value & 0x02 == 0;
+                let offset = &mut offsets[globals.report_id as usize];
+                if !constant {
+                    descriptor.fields.push(Field {
+                        report_id: globals.report_id,
+                        bit_offset: *offset,
+                        size: globals.report_size,
+                        count: globals.report_count,
+                        logical_min: globals.logical_min,
+                        array,
+                        usages: std::mem::take(&mut usages),
+                    });
+                }
+                *offset += globals.report_size * globals.report_count;
+                usages.clear();
+                usage_min = None;
+            }
+            // Output, Feature, Collection, End Collection
+            0x90 | 0xB0 | 0xA0 | 0xC0 => {
+                usages.clear();
+                usage_min = None;
+            }
+            0x04 => globals.usage_page = value as u16,
+            0x14 => globals.logical_min = signed,
+            0x74 => globals.report_size = value as usize,
+            0x84 => {
+                globals.report_id = value as u8;
+                descriptor.numbered = true;
+            }
+            0x94 => globals.report_count = value as usize,
+            0xA4 => stack.push(globals),
+            0xB4 => globals = stack.pop().unwrap_or_default(),
+            0x08 => usages.push(full_usage),
+            0x18 => usage_min = Some(full_usage),
+            0x28 => {
+                if let Some(min) = usage_min.take() {
+                    usages.extend(min..=full_usage);
+                }
+            }
+            _ => {}
+        }
+    }
+    descriptor
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Value {
+    // A key, button or consumer control that is down
+    Pressed(u32),
+    // A relative or absolute value such as a mouse axis, when non-zero
+    Axis(u32, i32),
+}
+
+impl Descriptor {
+    // Everything active in one input report. Keys reported in more than one
+    // field, like the boot and NKRO halves of a keyboard report, appear once.
+    pub fn decode(&self, report: &[u8]) -> Vec<Value> {
+        let (id, data) = match (self.numbered, report) {
+            (true, [id, data @ ..]) => (*id, data),
+            _ => (0, report),
+        };
+        let mut values = Vec::new();
+        for field in self.fields.iter().filter(|f| f.report_id == id) {
+            for i in 0..field.count {
+                let Some(raw) = read_bits(data, field.bit_offset + i * field.size, field.size)
+                else {
+                    break;
+                };
+                let value = if field.array {
+                    let index = raw as i64 - field.logical_min as i64;
+                    match usize::try_from(index).ok().and_then(|i| field.usages.get(i)) {
+                        // Usage 0 on any page means "nothing"
+                        Some(&usage) if usage & 0xFFFF != 0 => Value::Pressed(usage),
+                        _ => continue,
+                    }
+                } else {
+                    // Elements past the last usage reuse it
+                    let Some(&usage) = field.usages.get(i).or(field.usages.last()) else {
+                        continue;
+                    };
+                    if field.size == 1 {
+                        if raw == 0 {
+                            continue;
+                        }
+                        Value::Pressed(usage)
+                    } else {
+                        let value = sign_extend(raw, field.size, field.logical_min < 0);
+                        if value == 0 {
+                            continue;
+                        }
+                        Value::Axis(usage, value)
+                    }
+                };
+                if !values.contains(&value) {
+                    values.push(value);
+                }
+            }
+        }
+        values
+    }
+}
+
+// `size` bits starting at bit `offset`, least significant bit first as HID
+// reports are laid out; None if the report is too short
+fn read_bits(data: &[u8], offset: usize, size: usize) -> Option<u32> {
+    if size == 0 || size > 32 || offset + size > data.len() * 8 {
+        return None;
+    }
+    let mut value = 0u32;
+    for bit in 0..size {
+        let pos = offset + bit;
+        if data[pos / 8] & (1 << (pos % 8)) != 0 {
+            value |= 1 << bit;
+        }
+    }
+    Some(value)
+}
+
+fn sign_extend(raw: u32, size: usize, signed: bool) -> i32 {
+    if signed && size < 32 && raw & (1 << (size - 1)) != 0 {
+        (raw | !0 << size) as i32
+    } else {
+        raw as i32
+    }
+}
+
+// The boot keyboard layout, for when the device's descriptor can't be read
+pub const BOOT_KEYBOARD: &[u8] = &[
+    0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, // Generic Desktop, Keyboard, Application
+    0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, // Modifiers E0..E7
+    0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02, // 8 x 1 bit, Variable
+    0x95, 0x01, 0x75, 0x08, 0x81, 0x01, // Reserved byte
+    0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0x65, // 6 x 8 bits, 0..101
+    0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, // Key codes, Array
+    0xC0,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // usbd-human-interface-device's NKRO boot keyboard: boot modifiers,
+    // 7 constant bytes over the boot key array, then a key bitmap
+    const NKRO_KEYBOARD: &[u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x75, 0x01, 0x95, 0x08, 0x05, 0x07, 0x19, 0xE0,
+        0x29, 0xE7, 0x15, 0x00, 0x25, 0x01, 0x81, 0x02, 0x75, 0x38, 0x95, 0x01, 0x81, 0x01,
+        0x95, 0x05, 0x75, 0x01, 0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01,
+        0x75, 0x03, 0x91, 0x03, 0x95, 0x88, 0x75, 0x01, 0x15, 0x00, 0x25, 0x01, 0x05, 0x07,
+        0x19, 0x00, 0x29, 0x87, 0x81, 0x02, 0xC0,
+    ];
+
+    const CONSUMER: &[u8] = &[
+        0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01, 0x75, 0x10, 0x95, 0x04, 0x15, 0x00, 0x26, 0x9C,
+        0x02, 0x19, 0x00, 0x2A, 0x9C, 0x02, 0x81, 0x00, 0xC0,
+    ];
+
+    fn key(id: u16) -> Value {
+        Value::Pressed(usage(0x07, id))
+    }
+
+    #[test]
+    fn nkro_bitmap_and_all_modifiers() {
+        let descriptor = parse(NKRO_KEYBOARD);
+        let mut report = [0u8; 25];
+        // Right GUI and left Ctrl
+        report[0] = 0x81;
+        // A (0x04) and B (0x05) in the bitmap; the boot array is ignored
+        report[2] = 0x04;
+        report[8] = 0x30;
+        assert_eq!(descriptor.decode(&report), [key(0xE0), key(0xE7), key(0x04), key(0x05)]);
+        assert!(descriptor.decode(&[0u8; 25]).is_empty());
+    }
+
+    #[test]
+    fn boot_keys_are_an_array() {
+        let descriptor = parse(BOOT_KEYBOARD);
+        let report = [0x02, 0, 0x04, 0x04, 0x28, 0, 0, 0];
+        assert_eq!(descriptor.decode(&report), [key(0xE1), key(0x04), key(0x28)]);
+    }
+
+    #[test]
+    fn consumer_codes() {
+        let descriptor = parse(CONSUMER);
+        let report = [0xE9, 0x00, 0xCD, 0x00, 0, 0, 0, 0];
+        assert_eq!(
+            descriptor.decode(&report),
+            [Value::Pressed(usage(0x0C, 0xE9)), Value::Pressed(usage(0x0C, 0xCD))]
+        );
+    }
+
+    #[test]
+    fn signed_axes() {
+        assert_eq!(sign_extend(0xFF, 8, true), -1);
+        assert_eq!(sign_extend(0xFF, 8, false), 255);
+        assert_eq!(read_bits(&[0b1011_0000, 0b0000_0001], 4, 5), Some(0b1_1011));
+        assert_eq!(read_bits(&[0], 4, 5), None);
+    }
+}
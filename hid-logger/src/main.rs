@@ -1,15 +1,115 @@
-use hidapi::HidApi;
-use std::time::Duration;
+mod bench;
+mod capture;
+mod descriptor;
+mod serial;
+mod usages;
+
+use capture::{Recorder, Report};
+use descriptor::{Descriptor, Value};
+use hidapi::{DeviceInfo, HidApi, HidDevice};
+use std::collections::HashSet;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const VID: u16 = 0x239A;
+const PID: u16 = 0x8107;
+// USB serial number the firmware reports
+const SERIAL: &str = "VIBE001";
+
+// How often to look for the pad after it disconnects, and how long to wait
+// once it's back before opening it
+const RECONNECT_POLL: Duration = Duration::from_millis(250);
+const RECONNECT_SETTLE: Duration = Duration::from_millis(500);
+// How often to retry a source that failed while the rest of the pad kept
+// working, e.g. a serial port another program grabbed
+const REOPEN_POLL: Duration = Duration::from_secs(1);
+
+// Interfaces by the usage page and usage of their top-level collection
+const INTERFACES: &[(&str, u16, u16)] = &[
+    ("keyboard", 0x01, 0x06),
+    ("consumer", 0x0C, 0x01),
+    ("mouse", 0x01, 0x02),
+];
+const SOURCES: &[&str] = &["keyboard", "consumer", "mouse", "vendor", "serial"];
+
+const USAGE: &str = "\
+Usage: hid-logger [keyboard|consumer|mouse|vendor|serial ...] [--record <file.jsonl>]
+       hid-logger replay <file.jsonl>
+       hid-logger diff <old.jsonl> <new.jsonl>
+       hid-logger bench [--key <1-11>] [--runs <n>] [--text <text>] [--speed <hold:gap[:NKRO]>]";
 
 fn main() {
-    let api = HidApi::new().expect("Failed to create HID API");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["replay", path] => match capture::load(path) {
+            Ok(reports) => capture::print_timeline(&reports),
+            Err(e) => eprintln!("Could not read {}: {}", path, e),
+        },
+        ["diff", old, new] => {
+            let (old, new) = match (capture::load(old), capture::load(new)) {
+                (Ok(old), Ok(new)) => (old, new),
+                (Err(e), _) | (_, Err(e)) => {
+                    eprintln!("Could not read capture: {}", e);
+                    std::process::exit(2);
+                }
+            };
+            let changes = capture::diff(&old, &new);
+            capture::print_diff(&changes);
+            // Non-zero when the captures differ, for scripted comparisons
+            if !changes.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        ["bench", options @ ..] => match bench::Options::parse(options) {
+            Some(options) => bench::run(&options),
+            None => println!("{}", USAGE),
+        },
+        _ => log(&args),
+    }
+}
+
+// Logs every MacroPad interface, or just the named ones, in one timeline
+fn log(args: &[&str]) {
+    let mut wanted = Vec::new();
+    let mut record_path = None;
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--record" => match args.next() {
+                Some(&path) => record_path = Some(path),
+                None => {
+                    println!("{}", USAGE);
+                    return;
+                }
+            },
+            _ if SOURCES.contains(&arg) => wanted.push(arg),
+            _ => {
+                println!("{}", USAGE);
+                return;
+            }
+        }
+    }
+    // Nothing named means everything, including interfaces with no name
+    let wants = |name: &str| wanted.is_empty() || wanted.contains(&name);
+
+    let mut recorder = match record_path.map(Recorder::create).transpose() {
+        Ok(recorder) => recorder,
+        Err(e) => {
+            println!("Could not create capture file: {}", e);
+            return;
+        }
+    };
+
+    let mut api = HidApi::new().expect("Failed to create HID API");
 
     // List all HID devices
-    println!("Looking for MacroPad (VID: 0x239A, PID: 0x8107)...\n");
+    println!("Looking for MacroPad (VID: 0x{:04X}, PID: 0x{:04X})...\n", VID, PID);
 
     let mut found = false;
     for device in api.device_list() {
-        if device.vendor_id() == 0x239A && device.product_id() == 0x8107 {
+        if device.vendor_id() == VID && device.product_id() == PID {
             println!("Found MacroPad!");
             println!("  Manufacturer: {:?}", device.manufacturer_string());
             println!("  Product: {:?}", device.product_string());
@@ -34,59 +134,239 @@ fn main() {
         return;
     }
 
-    // Try to open the keyboard interface (usage page 0x01, usage 0x06)
-    let device = api.device_list()
-        .find(|d| d.vendor_id() == 0x239A && d.product_id() == 0x8107 && d.usage_page() == 0x01 && d.usage() == 0x06)
-        .and_then(|d| d.open_device(&api).ok());
+    // Each source reads on its own thread and sends what it sees here, so
+    // the timeline shows everything in the order it arrived
+    let start = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let mut live = open_sources(&api, &wants, start, &tx, false).len();
+    if live == 0 {
+        println!("Nothing to log. Try running with sudo or check permissions.");
+        return;
+    }
 
-    let device = match device {
-        Some(d) => d,
-        None => {
-            println!("Could not open MacroPad keyboard interface.");
-            println!("Try running with sudo or check permissions.");
-            return;
+    if let Some(path) = record_path {
+        println!("Recording to {}", path);
+    }
+    println!("Press keys on the macropad to see decoded HID reports...");
+    println!("Press Ctrl+C to exit.\n");
+
+    let mut last_us = 0;
+    let mut log_report = |report: Report| {
+        capture::print_report(&report, last_us);
+        last_us = report.t_us;
+        if let Some(recorder) = recorder.as_mut() {
+            if let Err(e) = recorder.record(&report) {
+                eprintln!("Error recording: {}", e);
+            }
         }
     };
 
-    println!("Opened MacroPad keyboard interface!");
-    println!("Press keys on the macropad to see raw HID reports...");
-    println!("Press Ctrl+C to exit.\n");
+    // Sources that have failed, with when, until they're reopened
+    let mut lost: Vec<(String, u64)> = Vec::new();
+    loop {
+        let event = match rx.recv_timeout(REOPEN_POLL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                if live > 0 && !lost.is_empty() {
+                    for line in reopen(&mut api, &mut lost, start, &tx, true) {
+                        live += 1;
+                        log_report(line);
+                    }
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!("the logger keeps a sender"),
+        };
+        match event {
+            Event::Report(report) => log_report(report),
+            Event::Lost(kind) => {
+                live -= 1;
+                let lost_us = micros(start);
+                log_report(usb_report(lost_us, "DISCONNECTED", kind.clone()));
+                lost.push((kind, lost_us));
+                // When every source has failed the pad is taken to be gone,
+                // e.g. reset by its watchdog; once it's back all are reopened
+                while live == 0 {
+                    wait_for_pad(&mut api);
+                    for line in reopen(&mut api, &mut lost, start, &tx, false) {
+                        live += 1;
+                        log_report(line);
+                    }
+                }
+            }
+        }
+    }
+}
 
-    let mut buf = [0u8; 64];
+pub enum Event {
+    Report(Report),
+    // The named source hit a read error and its thread has ended
+    Lost(String),
+}
 
-    // Set non-blocking mode
-    device.set_blocking_mode(false).ok();
+fn micros(start: Instant) -> u64 {
+    start.elapsed().as_micros() as u64
+}
 
+// Connection changes go in the timeline, and captures, as "usb" lines
+fn usb_report(t_us: u64, what: &str, decoded: String) -> Report {
+    Report {
+        t_us,
+        interface: "usb".to_string(),
+        data: what.to_string(),
+        decoded,
+    }
+}
+
+// This pad, rather than any board with the same VID/PID
+fn is_pad(info: &DeviceInfo) -> bool {
+    info.vendor_id() == VID && info.product_id() == PID && info.serial_number() == Some(SERIAL)
+}
+
+// Opens what it can of `lost`, returning a RECONNECTED line for each
+fn reopen(
+    api: &mut HidApi,
+    lost: &mut Vec<(String, u64)>,
+    start: Instant,
+    tx: &Sender<Event>,
+    quiet: bool,
+) -> Vec<Report> {
+    let _ = api.refresh_devices();
+    let names: Vec<String> = lost.iter().map(|(kind, _)| kind.clone()).collect();
+    let wanted = |kind: &str| names.iter().any(|name| name == kind);
+    let opened = open_sources(api, &wanted, start, tx, quiet);
+    let now_us = micros(start);
+    let mut lines = Vec::new();
+    lost.retain(|(kind, lost_us)| {
+        if !opened.contains(kind) {
+            return true;
+        }
+        let gap_ms = now_us.saturating_sub(*lost_us) as f64 / 1000.0;
+        let gap = format!("{} gap {:.1} ms", kind, gap_ms);
+        lines.push(usb_report(now_us, "RECONNECTED", gap));
+        false
+    });
+    lines
+}
+
+fn wait_for_pad(api: &mut HidApi) {
     loop {
-        match device.read_timeout(&mut buf, 100) {
-            Ok(len) if len > 0 => {
-                print!("Report ({} bytes): ", len);
-                for i in 0..len {
-                    print!("{:02X} ", buf[i]);
-                }
+        thread::sleep(RECONNECT_POLL);
+        if api.refresh_devices().is_ok() && api.device_list().any(is_pad) {
+            // Give the host a moment to finish setting up every interface
+            thread::sleep(RECONNECT_SETTLE);
+            return;
+        }
+    }
+}
+
+// Starts a reader thread for each wanted source and returns the names of
+// those that started. `quiet` leaves out sources that couldn't be opened.
+fn open_sources(
+    api: &HidApi,
+    wants: &dyn Fn(&str) -> bool,
+    start: Instant,
+    tx: &Sender<Event>,
+    quiet: bool,
+) -> Vec<String> {
+    let mut sources = Vec::new();
+    let mut paths = HashSet::new();
+    for info in api.device_list() {
+        if !is_pad(info) || !paths.insert(info.path()) {
+            continue;
+        }
+        let kind = interface_name(info.usage_page(), info.usage(), info.interface_number());
+        if !wants(&kind) {
+            continue;
+        }
+        match info.open_device(api) {
+            Ok(device) => {
+                println!("Opened MacroPad {} interface!", kind);
+                sources.push(kind.clone());
+                let tx = tx.clone();
+                thread::spawn(move || read_hid(kind, device, start, tx));
+            }
+            Err(e) if !quiet => println!("Could not open MacroPad {} interface: {}", kind, e),
+            Err(_) => {}
+        }
+    }
 
-                // Parse keyboard report
-                if len >= 8 {
-                    let modifier = buf[0];
-                    let keycode = buf[2]; // Standard keyboard report
+    if wants("serial") {
+        match serial::open(VID, PID, SERIAL) {
+            Ok((name, port)) => {
+                println!("Opened MacroPad serial port {}!", name);
+                let tx = tx.clone();
+                thread::spawn(move || serial::tail(port, start, tx));
+                sources.push("serial".to_string());
+            }
+            Err(e) if !quiet => println!("Could not open MacroPad serial port: {}", e),
+            Err(_) => {}
+        }
+    }
+    sources
+}
 
-                    print!(" | Mod: 0x{:02X}", modifier);
-                    if modifier & 0x01 != 0 { print!(" LCTRL"); }
-                    if modifier & 0x02 != 0 { print!(" LSHIFT"); }
-                    if modifier & 0x04 != 0 { print!(" LALT"); }
-                    if modifier & 0x08 != 0 { print!(" LGUI"); }
+// Known interfaces by name; vendor-defined pages (0xFF00 and up) are raw HID
+fn interface_name(usage_page: u16, usage: u16, number: i32) -> String {
+    match INTERFACES.iter().find(|&&(_, page, u)| page == usage_page && u == usage) {
+        Some(&(name, ..)) => name.to_string(),
+        None if usage_page >= 0xFF00 => "vendor".to_string(),
+        None => format!("if{}", number),
+    }
+}
 
-                    if keycode != 0 {
-                        print!(" | Key: 0x{:02X}", keycode);
-                    }
+// Decode reports by the layout the device itself declares
+fn read_descriptor(device: &HidDevice, kind: &str) -> Descriptor {
+    let mut raw_descriptor = [0u8; hidapi::MAX_REPORT_DESCRIPTOR_SIZE];
+    match device.get_report_descriptor(&mut raw_descriptor) {
+        Ok(len) => descriptor::parse(&raw_descriptor[..len]),
+        Err(e) => {
+            println!("Could not read the {} report descriptor ({}).", kind, e);
+            println!("Decoding {} reports as a boot keyboard.", kind);
+            descriptor::parse(descriptor::BOOT_KEYBOARD)
+        }
+    }
+}
+
+fn read_hid(kind: String, device: HidDevice, start: Instant, tx: Sender<Event>) {
+    let descriptor = read_descriptor(&device, &kind);
+
+    let mut buf = [0u8; 64];
+    loop {
+        match device.read_timeout(&mut buf, 100) {
+            Ok(len) if len > 0 => {
+                let report = Report {
+                    t_us: micros(start),
+                    interface: kind.clone(),
+                    data: capture::hex(&buf[..len]),
+                    decoded: describe(&descriptor, &buf[..len]),
+                };
+                if tx.send(Event::Report(report)).is_err() {
+                    return;
                 }
-                println!();
             }
             Ok(_) => {} // No data
             Err(e) => {
-                eprintln!("Error reading: {}", e);
-                std::thread::sleep(Duration::from_millis(100));
+                eprintln!("Error reading {}: {}", kind, e);
+                let _ = tx.send(Event::Lost(kind));
+                return;
             }
         }
     }
 }
+
+// e.g. "LSHIFT A", "VOLUME_UP" or "X=-3 WHEEL=+1"; "(none)" once all is released
+fn describe(descriptor: &Descriptor, report: &[u8]) -> String {
+    let values = descriptor.decode(report);
+    if values.is_empty() {
+        return "(none)".to_string();
+    }
+    let names: Vec<String> = values
+        .iter()
+        .map(|value| match *value {
+            Value::Pressed(usage) => usages::name(usage),
+            Value::Axis(usage, amount) => format!("{}={:+}", usages::name(usage), amount),
+        })
+        .collect();
+    names.join(" ")
+}
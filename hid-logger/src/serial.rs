@@ -0,0 +1,67 @@
+use crate::capture::Report;
+use crate::Event;
+use serialport::{SerialPort, SerialPortType};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+// The pad's CDC port, with event lines turned on so key presses show up
+// next to the HID reports they cause
+pub fn open(vid: u16, pid: u16, serial: &str) -> Result<(String, Box<dyn SerialPort>), String> {
+    let ports = serialport::available_ports().map_err(|e| e.to_string())?;
+    let name = ports
+        .into_iter()
+        .find(|p| match &p.port_type {
+            SerialPortType::UsbPort(usb) => {
+                usb.vid == vid && usb.pid == pid && usb.serial_number.as_deref() == Some(serial)
+            }
+            _ => false,
+        })
+        .map(|p| p.port_name)
+        .ok_or("no MacroPad serial port found")?;
+    let mut port = serialport::new(&name, 115_200)
+        .timeout(Duration::from_millis(100))
+        .open()
+        .map_err(|e| e.to_string())?;
+    // The pad only talks once DTR is up
+    port.write_data_terminal_ready(true).map_err(|e| e.to_string())?;
+    port.write_all(b"EVT:ON\n").map_err(|e| e.to_string())?;
+    Ok((name, port))
+}
+
+// Sends every line the pad writes, tagged "serial", until the port goes away
+pub fn tail(port: Box<dyn SerialPort>, start: Instant, tx: Sender<Event>) {
+    let mut reader = BufReader::new(port);
+    let mut line = Vec::new();
+    loop {
+        // A timeout can land mid-line; what was read so far stays in `line`
+        match reader.read_until(b'\n', &mut line) {
+            Ok(_) if line.ends_with(b"\n") => {
+                let text = String::from_utf8_lossy(&line).trim_end().to_string();
+                line.clear();
+                let report = Report {
+                    t_us: start.elapsed().as_micros() as u64,
+                    interface: "serial".to_string(),
+                    data: text,
+                    decoded: String::new(),
+                };
+                if tx.send(Event::Report(report)).is_err() {
+                    return;
+                }
+            }
+            // End of file: the port was unplugged
+            Ok(0) => {
+                eprintln!("Serial port closed");
+                let _ = tx.send(Event::Lost("serial".to_string()));
+                return;
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => {
+                eprintln!("Error reading serial: {}", e);
+                let _ = tx.send(Event::Lost("serial".to_string()));
+                return;
+            }
+        }
+    }
+}
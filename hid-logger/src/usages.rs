@@ -0,0 +1,134 @@
+// Readable names for the usages the MacroPad sends
+
+const MODIFIERS: [&str; 8] = ["LCTRL", "LSHIFT", "LALT", "LGUI", "RCTRL", "RSHIFT", "RALT", "RGUI"];
+
+pub fn name(usage: u32) -> String {
+    let page = (usage >> 16) as u16;
+    let id = usage as u16;
+    let known = match page {
+        0x01 => generic_desktop(id).map(str::to_string),
+        0x07 => key(id),
+        0x08 => led(id).map(str::to_string),
+        0x09 => Some(format!("BUTTON{}", id)),
+        0x0C => consumer(id).map(str::to_string),
+        _ => None,
+    };
+    known.unwrap_or_else(|| format!("0x{:04X}:0x{:04X}", page, id))
+}
+
+fn key(id: u16) -> Option<String> {
+    let name = match id {
+        0x04..=0x1D => ((b'A' + (id - 0x04) as u8) as char).to_string(),
+        0x1E..=0x26 => (id - 0x1D).to_string(),
+        0x27 => "0".to_string(),
+        0x3A..=0x45 => format!("F{}", id - 0x39),
+        0x59..=0x61 => format!("KP{}", id - 0x58),
+        0x68..=0x73 => format!("F{}", id - 0x5B),
+        0xE0..=0xE7 => MODIFIERS[(id - 0xE0) as usize].to_string(),
+        _ => {
+            let name = match id {
+                0x01 => "ROLLOVER",
+                0x28 => "ENTER",
+                0x29 => "ESC",
+                0x2A => "BSPC",
+                0x2B => "TAB",
+                0x2C => "SPACE",
+                0x2D => "MINUS",
+                0x2E => "EQUAL",
+                0x2F => "LBRC",
+                0x30 => "RBRC",
+                0x31 => "BSLS",
+                0x32 => "NUHS",
+                0x33 => "SCLN",
+                0x34 => "QUOT",
+                0x35 => "GRV",
+                0x36 => "COMM",
+                0x37 => "DOT",
+                0x38 => "SLSH",
+                0x39 => "CAPS",
+                0x46 => "PSCR",
+                0x47 => "SCRL",
+                0x48 => "PAUS",
+                0x49 => "INS",
+                0x4A => "HOME",
+                0x4B => "PGUP",
+                0x4C => "DEL",
+                0x4D => "END",
+                0x4E => "PGDN",
+                0x4F => "RIGHT",
+                0x50 => "LEFT",
+                0x51 => "DOWN",
+                0x52 => "UP",
+                0x53 => "NUM",
+                0x54 => "KP/",
+                0x55 => "KP*",
+                0x56 => "KP-",
+                0x57 => "KP+",
+                0x58 => "KPENTER",
+                0x62 => "KP0",
+                0x63 => "KP.",
+                0x64 => "NUBS",
+                0x65 => "APP",
+                _ => return None,
+            };
+            name.to_string()
+        }
+    };
+    Some(name)
+}
+
+fn consumer(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x30 => "POWER",
+        0x40 => "MENU",
+        0x6F => "BRIGHTNESS_UP",
+        0x70 => "BRIGHTNESS_DOWN",
+        0xB0 => "PLAY",
+        0xB1 => "PAUSE",
+        0xB2 => "RECORD",
+        0xB3 => "FAST_FORWARD",
+        0xB4 => "REWIND",
+        0xB5 => "NEXT_TRACK",
+        0xB6 => "PREV_TRACK",
+        0xB7 => "STOP",
+        0xB8 => "EJECT",
+        0xB9 => "RANDOM_PLAY",
+        0xBC => "REPEAT",
+        0xCD => "PLAY_PAUSE",
+        0xE2 => "MUTE",
+        0xE9 => "VOLUME_UP",
+        0xEA => "VOLUME_DOWN",
+        0x183 => "MEDIA_SELECT",
+        0x18A => "MAIL",
+        0x192 => "CALCULATOR",
+        0x194 => "FILE_BROWSER",
+        0x221 => "AC_SEARCH",
+        0x223 => "AC_HOME",
+        0x224 => "AC_BACK",
+        0x225 => "AC_FORWARD",
+        0x227 => "AC_REFRESH",
+        0x238 => "AC_PAN",
+        _ => return None,
+    })
+}
+
+fn generic_desktop(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x30 => "X",
+        0x31 => "Y",
+        0x32 => "Z",
+        0x38 => "WHEEL",
+        _ => return None,
+    })
+}
+
+fn led(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x01 => "NUM_LOCK",
+        0x02 => "CAPS_LOCK",
+        0x03 => "SCROLL_LOCK",
+        0x04 => "COMPOSE",
+        0x05 => "KANA",
+        _ => return None,
+    })
+}
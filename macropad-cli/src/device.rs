@@ -0,0 +1,182 @@
+use serialport::{ClearBuffer, SerialPort, SerialPortType};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::time::Duration;
+
+pub const VID: u16 = 0x239A;
+pub const PID: u16 = 0x8107;
+
+// Longest the pad takes to answer; saves to flash happen in the background
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    Serial(serialport::Error),
+    Io(io::Error),
+    Timeout,
+    // The port closed, e.g. the pad was unplugged
+    Disconnected,
+    // ERR:<code>:<reason> from the pad
+    Device { code: String, reason: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(
+                f,
+                "MacroPad not found (VID: 0x{:04X}, PID: 0x{:04X}); is it plugged in?",
+                VID, PID
+            ),
+            Error::Serial(e) => write!(f, "serial port: {}", e),
+            Error::Io(e) => write!(f, "serial port: {}", e),
+            Error::Timeout => write!(f, "no reply from the pad"),
+            Error::Disconnected => write!(f, "the pad disconnected"),
+            Error::Device { code, reason } => write!(f, "pad replied ERR {}: {}", code, reason),
+        }
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        Error::Serial(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+// CDC ports that belong to a MacroPad
+pub fn find_ports() -> Result<Vec<String>, Error> {
+    let ports = serialport::available_ports()?
+        .into_iter()
+        .filter(|p| match &p.port_type {
+            SerialPortType::UsbPort(usb) => usb.vid == VID && usb.pid == PID,
+            _ => false,
+        })
+        .map(|p| p.port_name)
+        .collect();
+    Ok(ports)
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Line<'a> {
+    // A query's data line, e.g. "SNP:4:git push"
+    Data(&'a str),
+    Ok,
+    Err { code: &'a str, reason: &'a str },
+    // An event, or a reply to some other command
+    Other,
+}
+
+// Sorts a line read from the pad by whether it answers command `seq`
+pub fn parse_line<'a>(line: &'a str, seq: &str) -> Line<'a> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let Some(body) = line
+        .strip_prefix('#')
+        .and_then(|l| l.strip_prefix(seq))
+        .and_then(|l| l.strip_prefix(':'))
+    else {
+        return Line::Other;
+    };
+    if body == "OK" {
+        return Line::Ok;
+    }
+    if let Some(err) = body.strip_prefix("ERR:") {
+        let (code, reason) = err.split_once(':').unwrap_or((err, ""));
+        return Line::Err { code, reason };
+    }
+    Line::Data(body)
+}
+
+pub struct Pad {
+    port: BufReader<Box<dyn SerialPort>>,
+    seq: u32,
+}
+
+impl Pad {
+    // Opens `port`, or the first MacroPad found if none is given
+    pub fn open(port: Option<&str>) -> Result<Self, Error> {
+        let name = match port {
+            Some(name) => name.to_string(),
+            None => find_ports()?.into_iter().next().ok_or(Error::NotFound)?,
+        };
+        let mut port = serialport::new(&name, 115_200).timeout(REPLY_TIMEOUT).open()?;
+        // The pad only talks once DTR is up
+        port.write_data_terminal_ready(true)?;
+        port.clear(ClearBuffer::Input)?;
+        Ok(Self {
+            port: BufReader::new(port),
+            // Varies per run so late replies to an earlier run never match
+            seq: std::process::id() % 1000 * 1000,
+        })
+    }
+
+    // Sends one command and returns its data lines, or the pad's error
+    pub fn command(&mut self, cmd: &str) -> Result<Vec<String>, Error> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq.to_string();
+        let port = self.port.get_mut();
+        port.write_all(format!("#{}:{}\n", seq, cmd).as_bytes())?;
+        port.flush()?;
+
+        let mut data = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.port.read_line(&mut line)? == 0 {
+                return Err(Error::Timeout);
+            }
+            match parse_line(&line, &seq) {
+                Line::Data(text) => data.push(text.to_string()),
+                Line::Ok => return Ok(data),
+                Line::Err { code, reason } => {
+                    return Err(Error::Device {
+                        code: code.to_string(),
+                        reason: reason.to_string(),
+                    })
+                }
+                Line::Other => {}
+            }
+        }
+    }
+
+    // Reads the next line the pad sends, e.g. an event
+    pub fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        if self.port.read_line(&mut line)? == 0 {
+            return Err(Error::Disconnected);
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_are_matched_by_sequence() {
+        assert_eq!(parse_line("#12:SNP:4:git push\r\n", "12"), Line::Data("SNP:4:git push"));
+        assert_eq!(parse_line("#12:OK\r\n", "12"), Line::Ok);
+        assert_eq!(parse_line("#1:OK\r\n", "12"), Line::Other);
+        assert_eq!(parse_line("#123:OK\r\n", "12"), Line::Other);
+        assert_eq!(parse_line("EVT:ENC:+1\r\n", "12"), Line::Other);
+    }
+
+    #[test]
+    fn errors_carry_code_and_reason() {
+        assert_eq!(
+            parse_line("#7:ERR:4:bad value\r\n", "7"),
+            Line::Err { code: "4", reason: "bad value" }
+        );
+        assert_eq!(parse_line("#7:ERR:9", "7"), Line::Err { code: "9", reason: "" });
+    }
+}
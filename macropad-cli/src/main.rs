@@ -0,0 +1,193 @@
+mod device;
+
+use device::{Error, Pad};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: macropad-cli [--port <path>] <command> [args]
+
+Commands:
+  ports                      List MacroPad serial ports
+  ping                       Check the pad answers
+  version                    Firmware and protocol version
+  msg <text>                 Show a message on the bottom line
+  clear                      Clear the message
+  status <idle|run|wait|err> Set the status icon
+  rgb <key> <rrggbb>         Override one key's LED color
+  brightness <0-255>         LED brightness
+  layer [vibe|media|snippet] Switch layer, or show the current one
+  layout [name]              Set or show the host layout
+  unicode [mode]             Set or show the Unicode input method
+  speed [hold:gap[:nkro]]    Set or show how fast snippets are typed
+  snippet <key> [text]       Set a snippet (empty text clears it), or show it
+  snippets                   Show all snippets as <key>:<text>
+  upload <file>              Set snippets from <key>:<text> lines, as `snippets` prints
+  macro <key> [chords]       Set a macro, e.g. GUI+C,TAB,GUI+V, or show it
+  macros                     Show all macros as <key>:<chords>
+  run <key>                  Type a snippet, or play its macro, as if pressed
+  events                     Print events from the pad until interrupted
+  reset                      Clear message, colors and status
+  send <line>                Send a raw protocol command";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let port = match args.iter().position(|a| a == "--port") {
+        Some(i) if i + 1 < args.len() => {
+            let port = args.remove(i + 1);
+            args.remove(i);
+            Some(port)
+        }
+        Some(_) => return usage("--port needs a path"),
+        None => None,
+    };
+    let Some((command, args)) = args.split_first() else {
+        return usage("missing command");
+    };
+
+    if command == "ports" {
+        return match device::find_ports() {
+            Ok(ports) if ports.is_empty() => report(Error::NotFound),
+            Ok(ports) => {
+                for port in ports {
+                    println!("{}", port);
+                }
+                ExitCode::SUCCESS
+            }
+            Err(e) => report(e),
+        };
+    }
+
+    let line = match protocol_line(command, args) {
+        Ok(line) => line,
+        Err(msg) => return usage(&msg),
+    };
+    let result = Pad::open(port.as_deref()).and_then(|mut pad| match command.as_str() {
+        "upload" => upload(&mut pad, &args[0]),
+        "events" => pad.command(&line).and_then(|_| events(&mut pad)),
+        _ => pad.command(&line).map(|data| print_data(command, &data)),
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => report(e),
+    }
+}
+
+// Builds the protocol command a subcommand sends
+fn protocol_line(command: &str, args: &[String]) -> Result<String, String> {
+    let line = match (command, args) {
+        ("ping", []) => "PING".to_string(),
+        ("version", []) => "VER?".to_string(),
+        ("msg", [_, ..]) => format!("MSG:{}", args.join(" ")),
+        ("clear", []) => "CLR:".to_string(),
+        ("status", [state]) => format!("STS:{}", state.to_uppercase()),
+        ("rgb", [key, color]) => format!("RGB:{}:{}", key, color.trim_start_matches('#')),
+        ("brightness", [level]) => format!("BRI:{}", level),
+        ("layer", []) => "LYR?".to_string(),
+        ("layer", [name]) => format!("LYR:{}", name.to_uppercase()),
+        ("layout", []) => "LAY?".to_string(),
+        ("layout", [name]) => format!("LAY:{}", name.to_uppercase()),
+        ("unicode", []) => "UNI?".to_string(),
+        ("unicode", [mode]) => format!("UNI:{}", mode.to_uppercase()),
+        ("speed", []) => "SPD?".to_string(),
+        ("speed", [pacing]) => format!("SPD:{}", pacing.to_uppercase()),
+        ("snippet", [key]) => format!("SNP?:{}", key),
+        ("snippet", [key, text @ ..]) => format!("SNP:{}:{}", key, text.join(" ")),
+        ("snippets", []) => "SNP?".to_string(),
+        // upload() sends one SNP: per line of the file
+        ("upload", [_]) => String::new(),
+        ("macro", [key]) => format!("MAC?:{}", key),
+        ("macro", [key, chords]) => format!("MAC:{}:{}", key, chords),
+        ("macros", []) => "MAC?".to_string(),
+        ("run", [key]) => format!("RUN:{}", key),
+        ("events", []) => "EVT:ON".to_string(),
+        ("reset", []) => "RST:".to_string(),
+        ("send", [_, ..]) => args.join(" "),
+        _ => return Err(format!("bad arguments for '{}'", command)),
+    };
+    // Commands are newline-terminated, so a line break would split one in two
+    if line.contains(['\r', '\n']) {
+        return Err("text can't contain line breaks; use {ENTER} in snippets".to_string());
+    }
+    Ok(line)
+}
+
+// Prints query replies without their protocol prefix, e.g. "VER:0.1.0:1"
+// as "0.1.0:1"; `send` prints them raw
+fn print_data(command: &str, data: &[String]) {
+    for line in data {
+        let text = match command {
+            "send" => line.as_str(),
+            _ => line.split_once(':').map_or(line.as_str(), |(_, rest)| rest),
+        };
+        println!("{}", text);
+    }
+}
+
+// Sets each snippet in `path`; blank lines and lines starting with '#' are skipped
+fn upload(pad: &mut Pad, path: &str) -> Result<(), Error> {
+    let text = std::fs::read_to_string(path)?;
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, snippet)) = line.split_once(':') else {
+            eprintln!("{}:{}: expected <key>:<text>, skipped", path, number + 1);
+            continue;
+        };
+        pad.command(&format!("SNP:{}:{}", key.trim(), snippet))
+            .inspect_err(|_| eprintln!("{}:{}: snippet {} not set", path, number + 1, key))?;
+        println!("Set snippet {}", key.trim());
+    }
+    Ok(())
+}
+
+fn events(pad: &mut Pad) -> Result<(), Error> {
+    loop {
+        match pad.read_line() {
+            Ok(line) if line.starts_with("EVT:") => println!("{}", line),
+            Ok(_) | Err(Error::Timeout) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn usage(msg: &str) -> ExitCode {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    ExitCode::from(2)
+}
+
+fn report(e: Error) -> ExitCode {
+    eprintln!("Error: {}", e);
+    ExitCode::FAILURE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(args: &[&str]) -> Result<String, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        protocol_line(&args[0], &args[1..])
+    }
+
+    #[test]
+    fn subcommands_map_to_protocol_verbs() {
+        assert_eq!(line(&["msg", "tests", "passing"]).unwrap(), "MSG:tests passing");
+        assert_eq!(line(&["status", "run"]).unwrap(), "STS:RUN");
+        assert_eq!(line(&["rgb", "3", "#00ff00"]).unwrap(), "RGB:3:00ff00");
+        assert_eq!(line(&["layer"]).unwrap(), "LYR?");
+        assert_eq!(line(&["layer", "media"]).unwrap(), "LYR:MEDIA");
+        assert_eq!(line(&["speed", "1:1:nkro"]).unwrap(), "SPD:1:1:NKRO");
+        assert_eq!(line(&["snippet", "4", "git", "push"]).unwrap(), "SNP:4:git push");
+        assert_eq!(line(&["snippet", "4", ""]).unwrap(), "SNP:4:");
+        assert_eq!(line(&["snippet", "4"]).unwrap(), "SNP?:4");
+    }
+
+    #[test]
+    fn bad_arguments_are_rejected() {
+        assert!(line(&["rgb", "3"]).is_err());
+        assert!(line(&["ping", "now"]).is_err());
+        assert!(line(&["frobnicate"]).is_err());
+        assert!(line(&["snippet", "4", "a\nb"]).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use heapless::Vec;
+
+use crate::NUM_KEYS;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DebounceMode {
+    // Report the first edge immediately, then ignore the pin for the
+    // debounce time. Lowest latency; assumes the switch isn't picking up noise.
+    Eager,
+    // Report a change only once the pin has held its new level for the
+    // debounce time. Filters noise at the cost of that much latency.
+    Deferred,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KeyEvent {
+    pub key: usize,
+    pub pressed: bool,
+}
+
+#[derive(Clone, Copy)]
+struct KeyDebounce {
+    stable: bool,
+    raw: bool,
+    since: u32, // ms of the last reported edge (eager) or raw change (deferred)
+}
+
+// Turns raw pin samples into clean press/release events for N switches.
+// Times are in ms from a free-running clock and may wrap.
+pub struct Debouncer<const N: usize = NUM_KEYS> {
+    mode: DebounceMode,
+    time_ms: u32,
+    keys: [KeyDebounce; N],
+}
+
+impl<const N: usize> Debouncer<N> {
+    pub fn new(mode: DebounceMode, time_ms: u32) -> Self {
+        Self {
+            mode,
+            time_ms,
+            keys: [KeyDebounce {
+                stable: false,
+                raw: false,
+                // Start outside the eager lockout so the first press counts
+                since: 0u32.wrapping_sub(time_ms),
+            }; N],
+        }
+    }
+
+    pub fn is_pressed(&self, key: usize) -> bool {
+        self.keys[key].stable
+    }
+
+    // Feed one sample of every key (true = pressed), returning the keys
+    // whose debounced state changed
+    pub fn update(&mut self, now_ms: u32, raw: [bool; N]) -> Vec<KeyEvent, N> {
+        let mut events = Vec::new();
+        for (key, (k, &pressed)) in self.keys.iter_mut().zip(raw.iter()).enumerate() {
+            let changed = match self.mode {
+                DebounceMode::Eager => {
+                    if pressed != k.stable && now_ms.wrapping_sub(k.since) >= self.time_ms {
+                        k.since = now_ms;
+                        true
+                    } else {
+                        false
+                    }
+                }
+                DebounceMode::Deferred => {
+                    if pressed != k.raw {
+                        k.raw = pressed;
+                        k.since = now_ms;
+                    }
+                    pressed != k.stable && now_ms.wrapping_sub(k.since) >= self.time_ms
+                }
+            };
+            if changed {
+                k.stable = pressed;
+                k.raw = pressed;
+                // Capacity is N, one event per key at most
+                let _ = events.push(KeyEvent { key, pressed });
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn only(key: usize, pressed: bool) -> [bool; NUM_KEYS] {
+        let mut raw = [false; NUM_KEYS];
+        raw[key] = pressed;
+        raw
+    }
+
+    fn press(key: usize) -> KeyEvent {
+        KeyEvent { key, pressed: true }
+    }
+
+    fn release(key: usize) -> KeyEvent {
+        KeyEvent { key, pressed: false }
+    }
+
+    #[test]
+    fn eager_reports_first_edge_and_ignores_chatter() {
+        let mut d = Debouncer::new(DebounceMode::Eager, 20);
+        assert_eq!(d.update(100, only(3, true)), [press(3)]);
+        assert!(d.update(105, only(3, false)).is_empty());
+        assert!(d.update(110, only(3, true)).is_empty());
+        assert!(d.is_pressed(3));
+        // Released once the lockout has passed
+        assert_eq!(d.update(130, only(3, false)), [release(3)]);
+        assert!(!d.is_pressed(3));
+    }
+
+    #[test]
+    fn eager_catches_up_after_lockout() {
+        let mut d = Debouncer::new(DebounceMode::Eager, 20);
+        d.update(0, only(0, true));
+        // A real release during the lockout is reported when it ends
+        assert!(d.update(10, only(0, false)).is_empty());
+        assert_eq!(d.update(20, only(0, false)), [release(0)]);
+    }
+
+    #[test]
+    fn deferred_waits_for_a_stable_level() {
+        let mut d = Debouncer::new(DebounceMode::Deferred, 20);
+        assert!(d.update(100, only(5, true)).is_empty());
+        assert!(d.update(110, only(5, false)).is_empty());
+        assert!(d.update(115, only(5, true)).is_empty());
+        assert!(d.update(130, only(5, true)).is_empty());
+        assert_eq!(d.update(135, only(5, true)), [press(5)]);
+        assert!(d.update(140, only(5, true)).is_empty());
+    }
+
+    #[test]
+    fn deferred_drops_glitches() {
+        let mut d = Debouncer::new(DebounceMode::Deferred, 20);
+        assert!(d.update(0, only(1, true)).is_empty());
+        assert!(d.update(10, only(1, false)).is_empty());
+        assert!(d.update(40, only(1, false)).is_empty());
+        assert!(!d.is_pressed(1));
+    }
+
+    #[test]
+    fn keys_are_independent() {
+        let mut d = Debouncer::new(DebounceMode::Eager, 20);
+        d.update(0, only(0, true));
+        let mut raw = only(0, true);
+        raw[11] = true;
+        assert_eq!(d.update(5, raw), [press(11)]);
+    }
+
+    #[test]
+    fn clock_wraparound() {
+        let mut d = Debouncer::new(DebounceMode::Deferred, 20);
+        assert!(d.update(u32::MAX - 5, only(2, true)).is_empty());
+        assert_eq!(d.update(15, only(2, true)), [press(2)]);
+    }
+}
@@ -0,0 +1,90 @@
+// Valid Gray-code moves between successive (a, b) samples, indexed by
+// prev << 2 | current; 0 covers both "no change" and skipped states
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+// Four quadrature transitions per mechanical detent
+const STEPS_PER_DETENT: i8 = 4;
+
+// Quadrature decoder for the rotary encoder. Feed it every pin sample and it
+// yields signed detent steps; bounces between two states cancel out.
+pub struct Quadrature {
+    prev: u8,
+    quarter: i8,
+}
+
+impl Quadrature {
+    pub fn new(a: bool, b: bool) -> Self {
+        Self {
+            prev: Self::bits(a, b),
+            quarter: 0,
+        }
+    }
+
+    fn bits(a: bool, b: bool) -> u8 {
+        ((a as u8) << 1) | b as u8
+    }
+
+    // Returns +1 (A leads B) or -1 when a full detent has been turned,
+    // otherwise 0
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        let current = Self::bits(a, b);
+        self.quarter += TRANSITIONS[((self.prev << 2) | current) as usize];
+        self.prev = current;
+
+        if self.quarter >= STEPS_PER_DETENT {
+            self.quarter = 0;
+            1
+        } else if self.quarter <= -STEPS_PER_DETENT {
+            self.quarter = 0;
+            -1
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One detent's worth of Gray code, starting and ending at rest
+    const FORWARD: [(bool, bool); 4] = [(true, false), (true, true), (false, true), (false, false)];
+
+    fn turn(q: &mut Quadrature, samples: impl Iterator<Item = (bool, bool)>) -> Vec<i8> {
+        samples
+            .map(|(a, b)| q.update(a, b))
+            .filter(|&step| step != 0)
+            .collect()
+    }
+
+    #[test]
+    fn forward_detent() {
+        let mut q = Quadrature::new(false, false);
+        assert_eq!(turn(&mut q, FORWARD.into_iter()), [1]);
+        assert_eq!(turn(&mut q, FORWARD.into_iter().cycle().take(8)), [1, 1]);
+    }
+
+    #[test]
+    fn reverse_detent() {
+        let mut q = Quadrature::new(false, false);
+        let reverse = [(false, true), (true, true), (true, false), (false, false)];
+        assert_eq!(turn(&mut q, reverse.into_iter()), [-1]);
+    }
+
+    #[test]
+    fn bounce_cancels_out() {
+        let mut q = Quadrature::new(false, false);
+        let chatter = [(true, false), (false, false), (true, false), (false, false)];
+        assert!(turn(&mut q, chatter.into_iter()).is_empty());
+        assert_eq!(turn(&mut q, FORWARD.into_iter()), [1]);
+    }
+
+    #[test]
+    fn held_and_skipped_samples_are_ignored() {
+        let mut q = Quadrature::new(false, false);
+        assert_eq!(q.update(false, false), 0);
+        // Jumping two states at once has no direction
+        assert_eq!(q.update(true, true), 0);
+        assert_eq!(q.quarter, 0);
+    }
+}
@@ -0,0 +1,126 @@
+// A release followed by a press within this window is a double-tap, so a
+// single tap is only reported once the window has closed
+pub const DOUBLE_TAP_MS: u32 = 300;
+// Holding this long is a long-press, reported without waiting for release
+pub const LONG_PRESS_MS: u32 = 600;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Gesture {
+    Tap,
+    DoubleTap,
+    LongPress,
+}
+
+impl Gesture {
+    pub fn name(self) -> &'static str {
+        match self {
+            Gesture::Tap => "TAP",
+            Gesture::DoubleTap => "DOUBLE",
+            Gesture::LongPress => "LONG",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Phase {
+    Idle,
+    Down(u32),     // first press, since ms
+    Released(u32), // first release, waiting for a second press since ms
+    SecondDown,
+    Held, // long-press already reported, waiting for release
+}
+
+// Turns debounced press/release edges of a single button into gestures
+pub struct GestureDetector {
+    phase: Phase,
+}
+
+impl Default for GestureDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GestureDetector {
+    pub fn new() -> Self {
+        Self { phase: Phase::Idle }
+    }
+
+    // Call every loop with the button's edge, if any; timeouts are only
+    // noticed on these calls
+    pub fn update(&mut self, now_ms: u32, edge: Option<bool>) -> Option<Gesture> {
+        let (phase, gesture) = match (self.phase, edge) {
+            (Phase::Idle, Some(true)) => (Phase::Down(now_ms), None),
+            (Phase::Down(_), Some(false)) => (Phase::Released(now_ms), None),
+            (Phase::Down(since), None) if now_ms.wrapping_sub(since) >= LONG_PRESS_MS => {
+                (Phase::Held, Some(Gesture::LongPress))
+            }
+            (Phase::Released(_), Some(true)) => (Phase::SecondDown, None),
+            (Phase::Released(since), None) if now_ms.wrapping_sub(since) >= DOUBLE_TAP_MS => {
+                (Phase::Idle, Some(Gesture::Tap))
+            }
+            (Phase::SecondDown, Some(false)) => (Phase::Idle, Some(Gesture::DoubleTap)),
+            (Phase::Held, Some(false)) => (Phase::Idle, None),
+            (phase, _) => (phase, None),
+        };
+        self.phase = phase;
+        gesture
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(edges: &[(u32, Option<bool>)]) -> Vec<Gesture> {
+        let mut detector = GestureDetector::new();
+        edges
+            .iter()
+            .filter_map(|&(now, edge)| detector.update(now, edge))
+            .collect()
+    }
+
+    #[test]
+    fn tap_waits_out_the_double_tap_window() {
+        let edges = [(0, Some(true)), (100, Some(false)), (300, None), (400, None)];
+        assert_eq!(run(&edges), [Gesture::Tap]);
+    }
+
+    #[test]
+    fn double_tap() {
+        let edges = [
+            (0, Some(true)),
+            (100, Some(false)),
+            (200, Some(true)),
+            (300, Some(false)),
+            (1000, None),
+        ];
+        assert_eq!(run(&edges), [Gesture::DoubleTap]);
+    }
+
+    #[test]
+    fn long_press_fires_while_held() {
+        let edges = [
+            (0, Some(true)),
+            (500, None),
+            (600, None),
+            (900, None),
+            (1000, Some(false)),
+            (2000, None),
+        ];
+        assert_eq!(run(&edges), [Gesture::LongPress]);
+    }
+
+    #[test]
+    fn slow_taps_are_separate() {
+        let edges = [
+            (0, Some(true)),
+            (100, Some(false)),
+            (500, None),
+            (600, Some(true)),
+            (700, Some(false)),
+            (1100, None),
+        ];
+        assert_eq!(run(&edges), [Gesture::Tap, Gesture::Tap]);
+    }
+}
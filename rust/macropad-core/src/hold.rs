@@ -0,0 +1,163 @@
+use heapless::Vec;
+
+use crate::keymap::KeyAction;
+use crate::NUM_KEYS;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HoldTiming {
+    // A TapHold key held at least this long takes its hold action
+    pub hold_ms: u32,
+    // Repeat keys fire once on press, again after the delay, then every interval
+    pub repeat_delay_ms: u32,
+    pub repeat_interval_ms: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Held {
+    Idle,
+    Repeating { action: KeyAction, next_at: u32 },
+    Pending { tap: KeyAction, hold: KeyAction, since: u32 },
+    // Hold action already fired, nothing left to do on release
+    Spent,
+}
+
+// Tracks held keys between debounced press and release so actions can
+// depend on how long a key is down. The action is captured at press time,
+// so a layer change mid-hold doesn't switch what the key does.
+pub struct HoldTracker {
+    timing: HoldTiming,
+    keys: [Held; NUM_KEYS],
+}
+
+impl HoldTracker {
+    pub fn new(timing: HoldTiming) -> Self {
+        Self {
+            timing,
+            keys: [Held::Idle; NUM_KEYS],
+        }
+    }
+
+    // Returns the action to run immediately, if any
+    pub fn press(&mut self, key: usize, action: KeyAction, now_ms: u32) -> Option<KeyAction> {
+        match action {
+            KeyAction::Repeat(_) => {
+                self.keys[key] = Held::Repeating {
+                    action,
+                    next_at: now_ms.wrapping_add(self.timing.repeat_delay_ms),
+                };
+                Some(action)
+            }
+            KeyAction::TapHold(&tap, &hold) => {
+                self.keys[key] = Held::Pending {
+                    tap,
+                    hold,
+                    since: now_ms,
+                };
+                None
+            }
+            _ => {
+                self.keys[key] = Held::Idle;
+                Some(action)
+            }
+        }
+    }
+
+    // A TapHold key released before the hold time runs its tap action
+    pub fn release(&mut self, key: usize) -> Option<KeyAction> {
+        let held = core::mem::replace(&mut self.keys[key], Held::Idle);
+        match held {
+            Held::Pending { tap, .. } => Some(tap),
+            _ => None,
+        }
+    }
+
+    // Call every loop; returns repeats and hold actions that are now due
+    pub fn poll(&mut self, now_ms: u32) -> Vec<KeyAction, NUM_KEYS> {
+        let mut due = Vec::new();
+        for held in self.keys.iter_mut() {
+            match *held {
+                Held::Repeating { action, next_at } if reached(now_ms, next_at) => {
+                    *held = Held::Repeating {
+                        action,
+                        next_at: now_ms.wrapping_add(self.timing.repeat_interval_ms),
+                    };
+                    let _ = due.push(action);
+                }
+                Held::Pending { hold, since, .. }
+                    if now_ms.wrapping_sub(since) >= self.timing.hold_ms =>
+                {
+                    *held = Held::Spent;
+                    let _ = due.push(hold);
+                }
+                _ => {}
+            }
+        }
+        due
+    }
+}
+
+// `deadline` has passed, allowing for clock wraparound
+fn reached(now_ms: u32, deadline: u32) -> bool {
+    now_ms.wrapping_sub(deadline) < u32::MAX / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usbd_human_interface_device::page::Keyboard;
+
+    const TIMING: HoldTiming = HoldTiming {
+        hold_ms: 200,
+        repeat_delay_ms: 400,
+        repeat_interval_ms: 50,
+    };
+    const UP: KeyAction = KeyAction::Repeat(&[Keyboard::UpArrow]);
+    const ENTER: KeyAction = KeyAction::Chord(&[Keyboard::ReturnEnter]);
+    const SHIFT_ENTER: KeyAction = KeyAction::Chord(&[Keyboard::LeftShift, Keyboard::ReturnEnter]);
+    const DUAL: KeyAction = KeyAction::TapHold(&ENTER, &SHIFT_ENTER);
+
+    #[test]
+    fn plain_actions_fire_on_press() {
+        let mut holds = HoldTracker::new(TIMING);
+        assert_eq!(holds.press(0, ENTER, 0), Some(ENTER));
+        assert!(holds.poll(1000).is_empty());
+        assert_eq!(holds.release(0), None);
+    }
+
+    #[test]
+    fn repeat_after_delay_then_at_interval() {
+        let mut holds = HoldTracker::new(TIMING);
+        assert_eq!(holds.press(6, UP, 0), Some(UP));
+        assert!(holds.poll(390).is_empty());
+        assert_eq!(holds.poll(400), [UP]);
+        assert!(holds.poll(440).is_empty());
+        assert_eq!(holds.poll(450), [UP]);
+        holds.release(6);
+        assert!(holds.poll(1000).is_empty());
+    }
+
+    #[test]
+    fn tap_hold_tapped() {
+        let mut holds = HoldTracker::new(TIMING);
+        assert_eq!(holds.press(4, DUAL, 0), None);
+        assert!(holds.poll(150).is_empty());
+        assert_eq!(holds.release(4), Some(ENTER));
+    }
+
+    #[test]
+    fn tap_hold_held() {
+        let mut holds = HoldTracker::new(TIMING);
+        assert_eq!(holds.press(4, DUAL, 0), None);
+        assert_eq!(holds.poll(200), [SHIFT_ENTER]);
+        assert!(holds.poll(600).is_empty());
+        assert_eq!(holds.release(4), None);
+    }
+
+    #[test]
+    fn repeat_survives_clock_wrap() {
+        let mut holds = HoldTracker::new(TIMING);
+        holds.press(0, UP, u32::MAX - 100);
+        assert!(holds.poll(u32::MAX).is_empty());
+        assert_eq!(holds.poll(300), [UP]);
+    }
+}
@@ -0,0 +1,245 @@
+use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+use crate::gesture::Gesture;
+use crate::menu::MenuAction;
+use crate::NUM_KEYS;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Layer {
+    Vibe,
+    Media,
+    Snippet,
+}
+
+impl Layer {
+    pub fn next(self) -> Self {
+        match self {
+            Layer::Vibe => Layer::Media,
+            Layer::Media => Layer::Vibe,
+            Layer::Snippet => Layer::Snippet, // encoder doesn't change snippet
+        }
+    }
+
+    pub fn prev(self) -> Self {
+        match self {
+            Layer::Vibe => Layer::Media,
+            Layer::Media => Layer::Vibe,
+            Layer::Snippet => Layer::Snippet,
+        }
+    }
+
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Layer::Vibe),
+            1 => Some(Layer::Media),
+            2 => Some(Layer::Snippet),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"VIBE" => Some(Layer::Vibe),
+            b"MEDIA" => Some(Layer::Media),
+            b"SNIPPET" => Some(Layer::Snippet),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layer::Vibe => "VIBE",
+            Layer::Media => "MEDIA",
+            Layer::Snippet => "SNIPPET",
+        }
+    }
+
+    pub fn keymap(self) -> &'static Keymap {
+        match self {
+            Layer::Vibe => &VIBE_KEYMAP,
+            Layer::Media => &MEDIA_KEYMAP,
+            Layer::Snippet => &SNIPPET_KEYMAP,
+        }
+    }
+
+    pub fn label(self, key: usize) -> &'static str {
+        self.keymap()[key].0
+    }
+
+    pub fn action(self, key: usize) -> KeyAction {
+        self.keymap()[key].1
+    }
+
+    // Action for one encoder detent, +1 clockwise
+    pub fn rotation(self, step: i8) -> KeyAction {
+        let map = match self {
+            Layer::Vibe => &VIBE_ENCODER,
+            Layer::Media => &MEDIA_ENCODER,
+            Layer::Snippet => &SNIPPET_ENCODER,
+        };
+        if step > 0 {
+            map.cw
+        } else {
+            map.ccw
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LayerAction {
+    ToggleSnippet,
+    Next,
+    Prev,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyAction {
+    // Press all keys together, then release
+    Chord(&'static [Keyboard]),
+    // Press and release each chord in turn
+    Taps(&'static [&'static [Keyboard]]),
+    // Like Chord, repeating while the key is held
+    Repeat(&'static [Keyboard]),
+    // First action if released quickly, second once held past the hold time
+    TapHold(&'static KeyAction, &'static KeyAction),
+    // Type a snippet from settings through the host layout mapping
+    Snippet(usize),
+    Layer(LayerAction),
+    Consumer(Consumer),
+    // Scroll the mouse wheel by this many notches, positive is up
+    Scroll(i8),
+    // Step LED brightness up or down
+    Brightness(i8),
+    // Write a line to the serial port instead of typing anything
+    Notify(&'static str),
+    // Start recording a macro, or cancel the one being recorded
+    Record,
+    // Work the settings menu
+    Menu(MenuAction),
+}
+
+pub type Keymap = [(&'static str, KeyAction); NUM_KEYS];
+
+pub struct EncoderMap {
+    pub ccw: KeyAction,
+    pub cw: KeyAction,
+}
+
+// Encoder push-button gestures, the same on every layer. While the settings
+// menu is open, State::button_action takes taps for the menu.
+pub fn button_action(gesture: Gesture) -> Option<KeyAction> {
+    match gesture {
+        Gesture::Tap => Some(KeyAction::Layer(LayerAction::ToggleSnippet)),
+        Gesture::DoubleTap => Some(KeyAction::Consumer(Consumer::Mute)),
+        // The menu's LAYER item is also the way off layers that rebind rotation
+        Gesture::LongPress => Some(KeyAction::Menu(MenuAction::Toggle)),
+    }
+}
+
+// Holding SNIP records a macro: keys pressed from then on are captured as
+// they're sent, and pressing a snippet key saves them to that slot. Macros
+// are keyboard chords only, so it's left off the MEDIA layer.
+const SNIP_OR_RECORD: KeyAction = KeyAction::TapHold(
+    &KeyAction::Layer(LayerAction::ToggleSnippet),
+    &KeyAction::Record,
+);
+
+pub static VIBE_KEYMAP: Keymap = [
+    ("REC", KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::LeftShift, Keyboard::R])),
+    ("STOP", KeyAction::Chord(&[Keyboard::Escape])),
+    ("CYCLE", KeyAction::Chord(&[Keyboard::LeftShift, Keyboard::Tab])),
+    ("ESC", KeyAction::Taps(&[&[Keyboard::Escape], &[Keyboard::Escape]])),
+    (
+        "ENTER",
+        KeyAction::TapHold(
+            &KeyAction::Chord(&[Keyboard::ReturnEnter]),
+            &KeyAction::Chord(&[Keyboard::LeftShift, Keyboard::ReturnEnter]),
+        ),
+    ),
+    ("TAB", KeyAction::Chord(&[Keyboard::Tab])),
+    ("UP", KeyAction::Repeat(&[Keyboard::UpArrow])),
+    ("DOWN", KeyAction::Repeat(&[Keyboard::DownArrow])),
+    ("SAVE", KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::S])),
+    ("COPY", KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::C])),
+    ("PASTE", KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::V])),
+    ("SNIP", SNIP_OR_RECORD),
+];
+
+pub static MEDIA_KEYMAP: Keymap = [
+    ("PREV", KeyAction::Consumer(Consumer::ScanPreviousTrack)),
+    ("PLAY", KeyAction::Consumer(Consumer::PlayPause)),
+    ("NEXT", KeyAction::Consumer(Consumer::ScanNextTrack)),
+    ("MUTE", KeyAction::Consumer(Consumer::Mute)),
+    ("VOL-", KeyAction::Consumer(Consumer::VolumeDecrement)),
+    ("VOL+", KeyAction::Consumer(Consumer::VolumeIncrement)),
+    ("RWD", KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::LeftArrow])),
+    ("STOP", KeyAction::Chord(&[Keyboard::Escape])),
+    ("FWD", KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::RightArrow])),
+    // Common mute-mic / toggle-camera shortcuts
+    ("MIC", KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::LeftShift, Keyboard::M])),
+    ("CAM", KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::LeftShift, Keyboard::V])),
+    ("SNIP", KeyAction::Layer(LayerAction::ToggleSnippet)),
+];
+
+pub static SNIPPET_KEYMAP: Keymap = [
+    ("!td", KeyAction::Snippet(0)),
+    ("!sh", KeyAction::Snippet(1)),
+    ("RKT", KeyAction::Snippet(2)),
+    ("SNP04", KeyAction::Snippet(3)),
+    ("SNP05", KeyAction::Snippet(4)),
+    ("SNP06", KeyAction::Snippet(5)),
+    ("SNP07", KeyAction::Snippet(6)),
+    ("SNP08", KeyAction::Snippet(7)),
+    ("SNP09", KeyAction::Snippet(8)),
+    ("SNP10", KeyAction::Snippet(9)),
+    ("SNP11", KeyAction::Snippet(10)),
+    ("EXIT", KeyAction::Layer(LayerAction::ToggleSnippet)),
+];
+
+pub static VIBE_ENCODER: EncoderMap = EncoderMap {
+    ccw: KeyAction::Layer(LayerAction::Prev),
+    cw: KeyAction::Layer(LayerAction::Next),
+};
+
+// Volume dial
+pub static MEDIA_ENCODER: EncoderMap = EncoderMap {
+    ccw: KeyAction::Consumer(Consumer::VolumeDecrement),
+    cw: KeyAction::Consumer(Consumer::VolumeIncrement),
+};
+
+pub static SNIPPET_ENCODER: EncoderMap = EncoderMap {
+    ccw: KeyAction::Scroll(-1),
+    cw: KeyAction::Scroll(1),
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_follows_layer() {
+        assert_eq!(Layer::Vibe.rotation(1), KeyAction::Layer(LayerAction::Next));
+        assert_eq!(Layer::Vibe.rotation(-1), KeyAction::Layer(LayerAction::Prev));
+        assert_eq!(Layer::Media.rotation(1), KeyAction::Consumer(Consumer::VolumeIncrement));
+        assert_eq!(Layer::Snippet.rotation(-1), KeyAction::Scroll(-1));
+    }
+
+    #[test]
+    fn encoder_cycle_skips_snippet() {
+        assert_eq!(Layer::Vibe.next(), Layer::Media);
+        assert_eq!(Layer::Media.next(), Layer::Vibe);
+        assert_eq!(Layer::Snippet.next(), Layer::Snippet);
+        assert_eq!(Layer::Vibe.prev(), Layer::Media);
+        assert_eq!(Layer::Media.prev(), Layer::Vibe);
+        assert_eq!(Layer::Snippet.prev(), Layer::Snippet);
+    }
+
+    #[test]
+    fn recording_starts_on_vibe_only() {
+        let snip = VIBE_KEYMAP.iter().position(|&(label, _)| label == "SNIP").unwrap();
+        assert!(matches!(Layer::Vibe.action(snip), KeyAction::TapHold(_, KeyAction::Record)));
+        // Media keys can't be recorded, so SNIP there only switches layer
+        let toggle = KeyAction::Layer(LayerAction::ToggleSnippet);
+        assert_eq!(Layer::Media.action(snip), toggle);
+    }
+}
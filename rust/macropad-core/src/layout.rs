@@ -0,0 +1,344 @@
+use usbd_human_interface_device::page::Keyboard;
+
+// Layout the host OS is set to. HID keycodes name physical US QWERTY
+// positions, so typing text means finding the position that produces each
+// char under the host's layout. Discriminants are persisted in settings.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HostLayout {
+    Colemak,
+    Qwerty,
+    Dvorak,
+    German,
+}
+
+// One char's worth of key presses: `key` with modifiers, followed by a
+// Space tap when the char sits on a dead key
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KeyStroke {
+    pub key: Keyboard,
+    pub shift: bool,
+    pub altgr: bool,
+    pub dead: bool,
+}
+
+impl KeyStroke {
+    const fn plain(key: Keyboard) -> Self {
+        KeyStroke {
+            key,
+            shift: false,
+            altgr: false,
+            dead: false,
+        }
+    }
+}
+
+// Physical positions in row order; each layout's `base` and `shift` strings
+// give the char produced at the same index. ' ' marks an unused position.
+const POSITIONS: [Keyboard; 49] = [
+    Keyboard::Grave,
+    Keyboard::Keyboard1,
+    Keyboard::Keyboard2,
+    Keyboard::Keyboard3,
+    Keyboard::Keyboard4,
+    Keyboard::Keyboard5,
+    Keyboard::Keyboard6,
+    Keyboard::Keyboard7,
+    Keyboard::Keyboard8,
+    Keyboard::Keyboard9,
+    Keyboard::Keyboard0,
+    Keyboard::Minus,
+    Keyboard::Equal,
+    Keyboard::Q,
+    Keyboard::W,
+    Keyboard::E,
+    Keyboard::R,
+    Keyboard::T,
+    Keyboard::Y,
+    Keyboard::U,
+    Keyboard::I,
+    Keyboard::O,
+    Keyboard::P,
+    Keyboard::LeftBrace,
+    Keyboard::RightBrace,
+    Keyboard::Backslash,
+    Keyboard::A,
+    Keyboard::S,
+    Keyboard::D,
+    Keyboard::F,
+    Keyboard::G,
+    Keyboard::H,
+    Keyboard::J,
+    Keyboard::K,
+    Keyboard::L,
+    Keyboard::Semicolon,
+    Keyboard::Apostrophe,
+    Keyboard::Z,
+    Keyboard::X,
+    Keyboard::C,
+    Keyboard::V,
+    Keyboard::B,
+    Keyboard::N,
+    Keyboard::M,
+    Keyboard::Comma,
+    Keyboard::Dot,
+    Keyboard::ForwardSlash,
+    // ISO only: the key left of Enter and the key right of left Shift
+    Keyboard::NonUSHash,
+    Keyboard::NonUSBackslash,
+];
+
+struct LayoutTable {
+    base: &'static str,
+    shift: &'static str,
+    altgr: &'static [(Keyboard, char)],
+    // Chars that only appear after the following keystroke
+    dead: &'static [char],
+}
+
+const QWERTY: LayoutTable = LayoutTable {
+    base: "`1234567890-=qwertyuiop[]\\asdfghjkl;'zxcvbnm,./",
+    shift: "~!@#$%^&*()_+QWERTYUIOP{}|ASDFGHJKL:\"ZXCVBNM<>?",
+    altgr: &[],
+    dead: &[],
+};
+
+const COLEMAK: LayoutTable = LayoutTable {
+    base: "`1234567890-=qwfpgjluy;[]\\arstdhneio'zxcvbkm,./",
+    shift: "~!@#$%^&*()_+QWFPGJLUY:{}|ARSTDHNEIO\"ZXCVBKM<>?",
+    altgr: &[],
+    dead: &[],
+};
+
+const DVORAK: LayoutTable = LayoutTable {
+    base: "`1234567890[]',.pyfgcrl/=\\aoeuidhtns-;qjkxbmwvz",
+    shift: "~!@#$%^&*(){}\"<>PYFGCRL?+|AOEUIDHTNS_:QJKXBMWVZ",
+    altgr: &[],
+    dead: &[],
+};
+
+// German QWERTZ (ISO). `^`, `´` and `` ` `` are dead keys.
+const GERMAN: LayoutTable = LayoutTable {
+    base: "^1234567890ß´qwertzuiopü+ asdfghjklöäyxcvbnm,.-#<",
+    shift: "°!\"§$%&/()=?`QWERTZUIOPÜ* ASDFGHJKLÖÄYXCVBNM;:_'>",
+    altgr: &[
+        (Keyboard::Keyboard2, '²'),
+        (Keyboard::Keyboard3, '³'),
+        (Keyboard::Keyboard7, '{'),
+        (Keyboard::Keyboard8, '['),
+        (Keyboard::Keyboard9, ']'),
+        (Keyboard::Keyboard0, '}'),
+        (Keyboard::Minus, '\\'),
+        (Keyboard::Q, '@'),
+        (Keyboard::E, '€'),
+        (Keyboard::RightBrace, '~'),
+        (Keyboard::M, 'µ'),
+        (Keyboard::NonUSBackslash, '|'),
+    ],
+    dead: &['^', '´', '`'],
+};
+
+impl HostLayout {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(HostLayout::Colemak),
+            1 => Some(HostLayout::Qwerty),
+            2 => Some(HostLayout::Dvorak),
+            3 => Some(HostLayout::German),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"COLEMAK" => Some(HostLayout::Colemak),
+            b"QWERTY" => Some(HostLayout::Qwerty),
+            b"DVORAK" => Some(HostLayout::Dvorak),
+            b"DE" => Some(HostLayout::German),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HostLayout::Colemak => "COLEMAK",
+            HostLayout::Qwerty => "QWERTY",
+            HostLayout::Dvorak => "DVORAK",
+            HostLayout::German => "DE",
+        }
+    }
+
+    fn table(self) -> &'static LayoutTable {
+        match self {
+            HostLayout::Colemak => &COLEMAK,
+            HostLayout::Qwerty => &QWERTY,
+            HostLayout::Dvorak => &DVORAK,
+            HostLayout::German => &GERMAN,
+        }
+    }
+
+    // Keys to press to type `c` on a host using this layout
+    pub fn key_for(self, c: char) -> Option<KeyStroke> {
+        match c {
+            ' ' => return Some(KeyStroke::plain(Keyboard::Space)),
+            '\n' => return Some(KeyStroke::plain(Keyboard::ReturnEnter)),
+            '\t' => return Some(KeyStroke::plain(Keyboard::Tab)),
+            _ => {}
+        }
+
+        let table = self.table();
+        let find = |chars: &str| {
+            POSITIONS
+                .iter()
+                .zip(chars.chars())
+                .find(|&(_, ch)| ch == c)
+                .map(|(&key, _)| key)
+        };
+        let (key, shift, altgr) = if let Some(key) = find(table.base) {
+            (key, false, false)
+        } else if let Some(key) = find(table.shift) {
+            (key, true, false)
+        } else {
+            let &(key, _) = table.altgr.iter().find(|&&(_, ch)| ch == c)?;
+            (key, false, true)
+        };
+
+        Some(KeyStroke {
+            key,
+            shift,
+            altgr,
+            dead: table.dead.contains(&c),
+        })
+    }
+}
+
+// How the host enters chars its layout has no key for, by code point.
+// Discriminants are persisted in settings.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UnicodeMode {
+    // Such chars are skipped
+    Off,
+    // Option + hex digits, with the "Unicode Hex Input" input source selected
+    MacOs,
+    // Ctrl+Shift+U, hex digits, Space (IBus and GTK)
+    Linux,
+    // Alt + keypad plus + hex digits, with EnableHexNumpad set in the registry
+    Windows,
+}
+
+impl UnicodeMode {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(UnicodeMode::Off),
+            1 => Some(UnicodeMode::MacOs),
+            2 => Some(UnicodeMode::Linux),
+            3 => Some(UnicodeMode::Windows),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"OFF" => Some(UnicodeMode::Off),
+            b"MAC" => Some(UnicodeMode::MacOs),
+            b"LINUX" => Some(UnicodeMode::Linux),
+            b"WIN" => Some(UnicodeMode::Windows),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UnicodeMode::Off => "OFF",
+            UnicodeMode::MacOs => "MAC",
+            UnicodeMode::Linux => "LINUX",
+            UnicodeMode::Windows => "WIN",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [HostLayout; 4] = [
+        HostLayout::Colemak,
+        HostLayout::Qwerty,
+        HostLayout::Dvorak,
+        HostLayout::German,
+    ];
+
+    fn stroke(key: Keyboard, shift: bool, altgr: bool) -> Option<KeyStroke> {
+        Some(KeyStroke {
+            key,
+            shift,
+            altgr,
+            dead: false,
+        })
+    }
+
+    #[test]
+    fn every_layout_covers_printable_ascii() {
+        for layout in ALL {
+            for b in 0x20..0x7F_u8 {
+                let c = b as char;
+                assert!(layout.key_for(c).is_some(), "{:?} misses {:?}", layout, c);
+            }
+        }
+    }
+
+    #[test]
+    fn tables_fit_positions() {
+        for layout in ALL {
+            let table = layout.table();
+            assert!(table.base.chars().count() <= POSITIONS.len());
+            assert_eq!(table.base.chars().count(), table.shift.chars().count());
+        }
+    }
+
+    #[test]
+    fn colemak_remaps_moved_letters() {
+        let l = HostLayout::Colemak;
+        assert_eq!(l.key_for('a'), stroke(Keyboard::A, false, false));
+        assert_eq!(l.key_for('o'), stroke(Keyboard::Semicolon, false, false));
+        assert_eq!(l.key_for('T'), stroke(Keyboard::F, true, false));
+        assert_eq!(l.key_for(':'), stroke(Keyboard::P, true, false));
+    }
+
+    #[test]
+    fn qwerty_and_dvorak_punctuation() {
+        let q = HostLayout::Qwerty;
+        assert_eq!(q.key_for('@'), stroke(Keyboard::Keyboard2, true, false));
+        assert_eq!(q.key_for('/'), stroke(Keyboard::ForwardSlash, false, false));
+        let d = HostLayout::Dvorak;
+        assert_eq!(d.key_for('-'), stroke(Keyboard::Apostrophe, false, false));
+        assert_eq!(d.key_for('('), stroke(Keyboard::Keyboard9, true, false));
+        assert_eq!(d.key_for('s'), stroke(Keyboard::Semicolon, false, false));
+    }
+
+    #[test]
+    fn german_uses_altgr_and_dead_keys() {
+        let de = HostLayout::German;
+        assert_eq!(de.key_for('z'), stroke(Keyboard::Y, false, false));
+        assert_eq!(de.key_for('@'), stroke(Keyboard::Q, false, true));
+        assert_eq!(de.key_for('|'), stroke(Keyboard::NonUSBackslash, false, true));
+        assert_eq!(de.key_for('#'), stroke(Keyboard::NonUSHash, false, false));
+        assert_eq!(de.key_for('ü'), stroke(Keyboard::LeftBrace, false, false));
+        let caret = de.key_for('^').unwrap();
+        assert_eq!(caret.key, Keyboard::Grave);
+        assert!(caret.dead);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for layout in ALL {
+            assert_eq!(HostLayout::from_name(layout.name().as_bytes()), Some(layout));
+            assert_eq!(HostLayout::from_u8(layout as u8), Some(layout));
+        }
+        assert_eq!(HostLayout::from_name(b"AZERTY"), None);
+    }
+
+    #[test]
+    fn unmapped_chars_are_dropped() {
+        assert_eq!(HostLayout::Qwerty.key_for('é'), None);
+    }
+}
@@ -0,0 +1,146 @@
+use smart_leds::RGB8;
+
+use crate::keymap::Layer;
+use crate::state::State;
+use crate::NUM_LEDS;
+
+fn lerp_color(a: RGB8, b: RGB8, t: u8) -> RGB8 {
+    let t16 = t as u16;
+    let inv_t = 255 - t16;
+    RGB8::new(
+        ((a.r as u16 * inv_t + b.r as u16 * t16) / 255) as u8,
+        ((a.g as u16 * inv_t + b.g as u16 * t16) / 255) as u8,
+        ((a.b as u16 * inv_t + b.b as u16 * t16) / 255) as u8,
+    )
+}
+
+fn vibe_gradient(pos: u8) -> RGB8 {
+    // purple -> cyan -> blue
+    let purple = RGB8::new(128, 0, 255);
+    let cyan = RGB8::new(0, 255, 255);
+    let blue = RGB8::new(0, 0, 255);
+
+    if pos < 128 {
+        lerp_color(purple, cyan, pos * 2)
+    } else {
+        lerp_color(cyan, blue, (pos - 128) * 2)
+    }
+}
+
+fn media_gradient(pos: u8) -> RGB8 {
+    // orange -> pink -> red
+    let orange = RGB8::new(255, 128, 0);
+    let pink = RGB8::new(255, 0, 128);
+    let red = RGB8::new(255, 0, 0);
+
+    if pos < 128 {
+        lerp_color(orange, pink, pos * 2)
+    } else {
+        lerp_color(pink, red, (pos - 128) * 2)
+    }
+}
+
+fn pulse(tick: u32) -> u8 {
+    // Sinusoidal pulse using lookup approximation
+    let phase = ((tick / 2) % 256) as u8;
+    if phase < 128 {
+        phase * 2
+    } else {
+        (255 - phase) * 2
+    }
+}
+
+fn pulse_green(tick: u32) -> RGB8 {
+    RGB8::new(0, pulse(tick), 0)
+}
+
+pub fn compute_leds(state: &State, tick: u32) -> [RGB8; NUM_LEDS] {
+    let mut leds = [RGB8::default(); NUM_LEDS];
+    let offset = (tick / 2) as u8;
+
+    match state.layer {
+        Layer::Vibe => {
+            for (i, led) in leds.iter_mut().take(11).enumerate() {
+                let pos = offset.wrapping_add((i as u8) * 23);
+                *led = vibe_gradient(pos);
+            }
+            leds[11] = pulse_green(tick);
+        }
+        Layer::Media => {
+            for (i, led) in leds.iter_mut().take(11).enumerate() {
+                let pos = offset.wrapping_add((i as u8) * 23);
+                *led = media_gradient(pos);
+            }
+            leds[11] = pulse_green(tick);
+        }
+        Layer::Snippet => {
+            for led in leds.iter_mut().take(11) {
+                *led = RGB8::new(255, 255, 255); // solid white
+            }
+            leds[11] = RGB8::new(0, 255, 0); // solid green
+        }
+    }
+
+    // Apply custom colors if set
+    if let Some(ref custom) = state.settings.colors {
+        for (led, &color) in leds.iter_mut().zip(custom.iter()) {
+            if color.r != 0 || color.g != 0 || color.b != 0 {
+                *led = color;
+            }
+        }
+    }
+
+    // The SNIP key pulses red while a macro is being recorded
+    if state.recording.is_some() {
+        leds[11] = RGB8::new(pulse(tick), 0, 0);
+    }
+
+    leds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    #[test]
+    fn gradient_endpoints() {
+        assert_eq!(vibe_gradient(0), RGB8::new(128, 0, 255));
+        assert_eq!(media_gradient(0), RGB8::new(255, 128, 0));
+    }
+
+    #[test]
+    fn snippet_layer_is_solid() {
+        let mut state = State::new(Settings::defaults());
+        state.toggle_snippet();
+        let leds = compute_leds(&state, 1234);
+        assert!(leds[..11].iter().all(|&c| c == RGB8::new(255, 255, 255)));
+        assert_eq!(leds[11], RGB8::new(0, 255, 0));
+    }
+
+    #[test]
+    fn layers_animate_over_time() {
+        let state = State::new(Settings::defaults());
+        assert_ne!(compute_leds(&state, 0)[0], compute_leds(&state, 100)[0]);
+    }
+
+    #[test]
+    fn recording_pulses_red() {
+        let mut state = State::new(Settings::defaults());
+        state.start_recording();
+        let leds = compute_leds(&state, 100);
+        assert_eq!(leds[11], RGB8::new(100, 0, 0));
+    }
+
+    #[test]
+    fn custom_colors_override_all_but_black() {
+        let mut state = State::new(Settings::defaults());
+        let mut colors = [RGB8::default(); NUM_LEDS];
+        colors[3] = RGB8::new(10, 20, 30);
+        state.settings.colors = Some(colors);
+        let leds = compute_leds(&state, 0);
+        let plain = compute_leds(&State::new(Settings::defaults()), 0);
+        assert_eq!(leds[3], RGB8::new(10, 20, 30));
+        assert_eq!(leds[4], plain[4]);
+    }
+}
@@ -0,0 +1,29 @@
+//! Platform-independent MacroPad logic: keymaps, layer state, the serial
+//! protocol, settings encoding, macros, key debouncing and LED effects. The
+//! firmware binary wires these to the RP2040 peripherals; everything here
+//! builds and tests on the host with `cargo test`.
+#![cfg_attr(not(test), no_std)]
+
+pub mod debounce;
+pub mod encoder;
+pub mod gesture;
+pub mod hold;
+pub mod keymap;
+pub mod layout;
+pub mod leds;
+pub mod macros;
+pub mod menu;
+pub mod protocol;
+pub mod scheduler;
+pub mod settings;
+pub mod state;
+
+pub const NUM_KEYS: usize = 12;
+pub const NUM_LEDS: usize = 12;
+pub const SNIPPET_COUNT: usize = 11;
+pub const SNIPPET_LEN: usize = 64;
+pub const SNIPPET_LABEL_LEN: usize = 6;
+pub const MESSAGE_LEN: usize = 20;
+// Room for the longest snippet command, SNP:<key>:<snippet>, and for most
+// MAC:<key>:<chords> macros; longer macros can only be recorded on the pad
+pub const SERIAL_BUF_LEN: usize = 160;
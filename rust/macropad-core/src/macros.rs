@@ -0,0 +1,317 @@
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::scheduler::{Pacing, MAX_REPORT_KEYS};
+
+// Chords per recorded macro; keys pressed past this are dropped
+pub const MACRO_STEPS: usize = 16;
+// Longest name key_name gives, "RSHIFT"
+pub const KEY_NAME_LEN: usize = 6;
+// Longest macro as format_macro writes it: every chord full of the longest
+// names, each followed by a '+' or ','
+pub const MACRO_TEXT_LEN: usize = MACRO_STEPS * MAX_REPORT_KEYS * (KEY_NAME_LEN + 1);
+
+// Keys pressed together, then released
+pub type Chord = Vec<Keyboard, MAX_REPORT_KEYS>;
+// A recorded sequence of chords, played back one after another
+pub type Macro = Vec<Chord, MACRO_STEPS>;
+
+// Names for keys that aren't a letter, digit or F-key
+const NAMED_KEYS: &[(&str, Keyboard)] = &[
+    ("CTRL", Keyboard::LeftControl),
+    ("SHIFT", Keyboard::LeftShift),
+    ("ALT", Keyboard::LeftAlt),
+    ("GUI", Keyboard::LeftGUI),
+    ("RCTRL", Keyboard::RightControl),
+    ("RSHIFT", Keyboard::RightShift),
+    ("RALT", Keyboard::RightAlt),
+    ("RGUI", Keyboard::RightGUI),
+    ("ENTER", Keyboard::ReturnEnter),
+    ("ESC", Keyboard::Escape),
+    ("BSPC", Keyboard::DeleteBackspace),
+    ("TAB", Keyboard::Tab),
+    ("SPACE", Keyboard::Space),
+    ("MINUS", Keyboard::Minus),
+    ("EQUAL", Keyboard::Equal),
+    ("LBRC", Keyboard::LeftBrace),
+    ("RBRC", Keyboard::RightBrace),
+    ("BSLS", Keyboard::Backslash),
+    ("SCLN", Keyboard::Semicolon),
+    ("QUOT", Keyboard::Apostrophe),
+    ("GRV", Keyboard::Grave),
+    ("COMM", Keyboard::Comma),
+    ("DOT", Keyboard::Dot),
+    ("SLSH", Keyboard::ForwardSlash),
+    ("CAPS", Keyboard::CapsLock),
+    ("INS", Keyboard::Insert),
+    ("HOME", Keyboard::Home),
+    ("PGUP", Keyboard::PageUp),
+    ("DEL", Keyboard::DeleteForward),
+    ("END", Keyboard::End),
+    ("PGDN", Keyboard::PageDown),
+    ("RIGHT", Keyboard::RightArrow),
+    ("LEFT", Keyboard::LeftArrow),
+    ("DOWN", Keyboard::DownArrow),
+    ("UP", Keyboard::UpArrow),
+];
+
+const DIGITS: &[u8; 10] = b"1234567890";
+
+// Key by name, e.g. "A", "7", "F5", "GUI" or "ENTER"; case-insensitive
+pub fn key_from_name(name: &[u8]) -> Option<Keyboard> {
+    match name {
+        [c] if c.is_ascii_alphabetic() => {
+            Some(Keyboard::from(u8::from(Keyboard::A) + (c.to_ascii_uppercase() - b'A')))
+        }
+        [c] if c.is_ascii_digit() => {
+            let offset = DIGITS.iter().position(|d| d == c)? as u8;
+            Some(Keyboard::from(u8::from(Keyboard::Keyboard1) + offset))
+        }
+        [b'F' | b'f', n @ ..] if !n.is_empty() && n.len() <= 2 => {
+            let n = core::str::from_utf8(n).ok()?.parse::<u8>().ok()?;
+            (1..=12).contains(&n).then(|| Keyboard::from(u8::from(Keyboard::F1) + n - 1))
+        }
+        _ => NAMED_KEYS
+            .iter()
+            .find(|(known, _)| known.as_bytes().eq_ignore_ascii_case(name))
+            .map(|&(_, key)| key),
+    }
+}
+
+// Inverse of key_from_name, written to `buf`
+pub fn key_name(key: Keyboard, buf: &mut [u8; 3]) -> Option<&str> {
+    let code = u8::from(key);
+    let len = if (Keyboard::A..=Keyboard::Z).contains(&key) {
+        buf[0] = b'A' + (code - u8::from(Keyboard::A));
+        1
+    } else if (Keyboard::Keyboard1..=Keyboard::Keyboard0).contains(&key) {
+        buf[0] = DIGITS[(code - u8::from(Keyboard::Keyboard1)) as usize];
+        1
+    } else if (Keyboard::F1..=Keyboard::F12).contains(&key) {
+        let n = code - u8::from(Keyboard::F1) + 1;
+        buf[0] = b'F';
+        if n < 10 {
+            buf[1] = b'0' + n;
+            2
+        } else {
+            buf[1] = b'1';
+            buf[2] = b'0' + n - 10;
+            3
+        }
+    } else {
+        return NAMED_KEYS.iter().find(|&&(_, k)| k == key).map(|&(name, _)| name);
+    };
+    core::str::from_utf8(&buf[..len]).ok()
+}
+
+// Parses "GUI+C,TAB,GUI+V": chords separated by commas, keys within a chord
+// by '+'. Empty text is an empty macro.
+pub fn parse_macro(text: &[u8]) -> Option<Macro> {
+    let mut steps = Macro::new();
+    if text.is_empty() {
+        return Some(steps);
+    }
+    for chord_text in text.split(|&c| c == b',') {
+        steps.push(parse_chord(chord_text)?).ok()?;
+    }
+    Some(steps)
+}
+
+// Writes a macro in the form parse_macro reads. Returns None if `out`
+// runs out of room.
+pub fn format_macro<const N: usize>(steps: &Macro, out: &mut Vec<u8, N>) -> Option<()> {
+    for (i, chord) in steps.iter().enumerate() {
+        if i > 0 {
+            out.push(b',').ok()?;
+        }
+        for (j, &key) in chord.iter().enumerate() {
+            if j > 0 {
+                out.push(b'+').ok()?;
+            }
+            let mut buf = [0u8; 3];
+            out.extend_from_slice(key_name(key, &mut buf)?.as_bytes()).ok()?;
+        }
+    }
+    Some(())
+}
+
+// Longest {DELAY ms} accepted, so one escape can't stall the queue for long
+pub const MAX_DELAY_MS: u32 = 10_000;
+
+// One piece of snippet text. Snippets are typed as written except for
+// escapes in braces:
+//   {ENTER}, {GUI+SPACE}  press a chord, named as in key_from_name
+//   {KEY X}               press a letter or digit key on its own
+//   {DELAY 200}           pause for 200 ms
+//   {HOLD SHIFT}          keep keys down until {RELEASE} or the end
+//   {RELEASE}             let go of held keys
+//   {SPEED 5 5 NKRO}      type the rest with this pacing, as for SPD:
+//   {{                    a literal '{'
+// Anything else in braces is typed literally, so code snippets like
+// "fn main() {}" or "${x}" need no escaping.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Token {
+    Char(char),
+    Chord(Chord),
+    Delay(u32),
+    Hold(Chord),
+    Release,
+    Speed(Pacing),
+}
+
+// Splits the next token off `text`, returning it with the text after it
+pub fn next_token(text: &str) -> Option<(Token, &str)> {
+    let c = text.chars().next()?;
+    let rest = &text[c.len_utf8()..];
+    if c != '{' {
+        return Some((Token::Char(c), rest));
+    }
+    if let Some(rest) = rest.strip_prefix('{') {
+        return Some((Token::Char('{'), rest));
+    }
+    let escape = rest.find('}').and_then(|end| {
+        let token = parse_escape(&rest[..end])?;
+        Some((token, &rest[end + 1..]))
+    });
+    Some(escape.unwrap_or((Token::Char('{'), rest)))
+}
+
+fn parse_escape(body: &str) -> Option<Token> {
+    let (word, arg) = body.split_once(' ').unwrap_or((body, ""));
+    if word.eq_ignore_ascii_case("DELAY") {
+        let ms = arg.parse::<u32>().ok()?;
+        return (ms <= MAX_DELAY_MS).then_some(Token::Delay(ms));
+    }
+    if word.eq_ignore_ascii_case("HOLD") {
+        return Some(Token::Hold(parse_chord(arg.as_bytes())?));
+    }
+    if word.eq_ignore_ascii_case("SPEED") {
+        return Some(Token::Speed(Pacing::parse(arg.as_bytes(), b' ')?));
+    }
+    if word.eq_ignore_ascii_case("KEY") {
+        return Some(Token::Chord(parse_chord(arg.as_bytes())?));
+    }
+    if body.eq_ignore_ascii_case("RELEASE") {
+        return Some(Token::Release);
+    }
+    // A lone letter or digit in braces is far more often code than a key
+    // press, and would skip the host layout, so it needs {KEY x}
+    if body.len() == 1 {
+        return None;
+    }
+    Some(Token::Chord(parse_chord(body.as_bytes())?))
+}
+
+fn parse_chord(text: &[u8]) -> Option<Chord> {
+    let mut chord = Chord::new();
+    for name in text.split(|&c| c == b'+') {
+        chord.push(key_from_name(name)?).ok()?;
+    }
+    Some(chord)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(keys: &[Keyboard]) -> Chord {
+        Chord::from_slice(keys).unwrap()
+    }
+
+    #[test]
+    fn key_names_round_trip() {
+        for name in ["A", "Z", "1", "0", "F1", "F12", "GUI", "ENTER", "PGDN"] {
+            let key = key_from_name(name.as_bytes()).unwrap();
+            let mut buf = [0u8; 3];
+            assert_eq!(key_name(key, &mut buf), Some(name));
+        }
+        assert_eq!(key_from_name(b"f5"), Some(Keyboard::F5));
+        assert_eq!(key_from_name(b"esc"), Some(Keyboard::Escape));
+        assert_eq!(key_from_name(b"F13"), None);
+        assert_eq!(key_from_name(b"HYPER"), None);
+        assert!(NAMED_KEYS.iter().all(|(name, _)| name.len() <= KEY_NAME_LEN));
+    }
+
+    #[test]
+    fn parse_chords() {
+        let steps = parse_macro(b"GUI+C,tab,GUI+V").unwrap();
+        assert_eq!(
+            steps.as_slice(),
+            [
+                chord(&[Keyboard::LeftGUI, Keyboard::C]),
+                chord(&[Keyboard::Tab]),
+                chord(&[Keyboard::LeftGUI, Keyboard::V]),
+            ]
+        );
+        let mut text: Vec<u8, 64> = Vec::new();
+        format_macro(&steps, &mut text).unwrap();
+        assert_eq!(text, b"GUI+C,TAB,GUI+V");
+    }
+
+    #[test]
+    fn parse_rejections() {
+        assert!(parse_macro(b"").unwrap().is_empty());
+        assert!(parse_macro(b"GUI+").is_none());
+        assert!(parse_macro(b"A,,B").is_none());
+        assert!(parse_macro(b"A+B+C+D+E+F+G").is_none());
+        let too_many = "A,".repeat(MACRO_STEPS) + "A";
+        assert!(parse_macro(too_many.as_bytes()).is_none());
+    }
+
+    fn tokens(text: &str) -> Vec<Token, 16> {
+        let mut text = text;
+        let mut out = Vec::new();
+        while let Some((token, rest)) = next_token(text) {
+            out.push(token).unwrap();
+            text = rest;
+        }
+        out
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            tokens("{gui+space}a{DELAY 200}{ENTER}"),
+            [
+                Token::Chord(chord(&[Keyboard::LeftGUI, Keyboard::Space])),
+                Token::Char('a'),
+                Token::Delay(200),
+                Token::Chord(chord(&[Keyboard::ReturnEnter])),
+            ]
+        );
+        assert_eq!(
+            tokens("{HOLD SHIFT}é{RELEASE}"),
+            [
+                Token::Hold(chord(&[Keyboard::LeftShift])),
+                Token::Char('é'),
+                Token::Release,
+            ]
+        );
+        let fast = Pacing {
+            hold_ms: 2,
+            gap_ms: 1,
+            rollover: true,
+        };
+        assert_eq!(tokens("{speed 2 1 nkro}"), [Token::Speed(fast)]);
+        assert_eq!(
+            tokens("{KEY x}{gui+1}"),
+            [
+                Token::Chord(chord(&[Keyboard::X])),
+                Token::Chord(chord(&[Keyboard::LeftGUI, Keyboard::Keyboard1])),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_escapes_are_literal() {
+        let literal = |text: &str| text.chars().map(Token::Char).collect::<Vec<Token, 16>>();
+        assert_eq!(tokens("{}"), literal("{}"));
+        assert_eq!(tokens("{ x }"), literal("{ x }"));
+        assert_eq!(tokens("{x}"), [Token::Char('{'), Token::Char('x'), Token::Char('}')]);
+        assert_eq!(tokens("${0}"), literal("${0}"));
+        assert_eq!(tokens("{DELAY 99999}"), literal("{DELAY 99999}"));
+        assert_eq!(tokens("{SPEED 0 5}"), literal("{SPEED 0 5}"));
+        assert_eq!(tokens("{ENTER"), literal("{ENTER"));
+        assert_eq!(tokens("{{ENTER}"), literal("{ENTER}"));
+    }
+}
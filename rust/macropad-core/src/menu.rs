@@ -0,0 +1,253 @@
+use core::fmt::Write;
+use heapless::String;
+
+use crate::keymap::Layer;
+use crate::layout::{HostLayout, UnicodeMode};
+use crate::scheduler::Pacing;
+use crate::state::State;
+
+// Chars across the display in the 6x10 font
+pub const MENU_LINE_LEN: usize = 21;
+
+// Brightness changes by this much per detent
+const BRIGHTNESS_STEP: i8 = 16;
+
+// Typing speeds the menu steps through; SPD: can set any other
+const SPEEDS: [(&str, Pacing); 4] = [
+    ("SLOW", Pacing { hold_ms: 50, gap_ms: 40, rollover: false }),
+    ("NORMAL", Pacing::DEFAULT),
+    ("FAST", Pacing { hold_ms: 8, gap_ms: 4, rollover: false }),
+    ("NKRO", Pacing { hold_ms: 1, gap_ms: 1, rollover: true }),
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MenuItem {
+    Layer,
+    Brightness,
+    Layout,
+    Unicode,
+    Speed,
+}
+
+// One item per display row
+pub const MENU_ITEMS: [MenuItem; 5] = [
+    MenuItem::Layer,
+    MenuItem::Brightness,
+    MenuItem::Layout,
+    MenuItem::Unicode,
+    MenuItem::Speed,
+];
+
+impl MenuItem {
+    fn name(self) -> &'static str {
+        match self {
+            MenuItem::Layer => "LAYER",
+            MenuItem::Brightness => "BRIGHT",
+            MenuItem::Layout => "LAYOUT",
+            MenuItem::Unicode => "UNICODE",
+            MenuItem::Speed => "SPEED",
+        }
+    }
+}
+
+// The on-pad settings menu, opened with a long-press of the encoder button.
+// Turning the knob moves between items; a tap starts or stops changing the
+// selected one, and turning then changes its value.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Menu {
+    pub selected: usize,
+    pub editing: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MenuAction {
+    // Open the menu, or close it if open
+    Toggle,
+    // Start or stop changing the selected item
+    Select,
+    // One encoder detent, +1 clockwise
+    Turn(i8),
+}
+
+pub fn run(state: &mut State, action: MenuAction) {
+    state.display_dirty = true;
+    let Some(menu) = state.menu.as_mut() else {
+        if action == MenuAction::Toggle {
+            state.menu = Some(Menu::default());
+        }
+        return;
+    };
+    match action {
+        MenuAction::Toggle => state.menu = None,
+        MenuAction::Select => menu.editing = !menu.editing,
+        MenuAction::Turn(step) if menu.editing => {
+            let item = MENU_ITEMS[menu.selected];
+            change(state, item, step);
+        }
+        MenuAction::Turn(step) => {
+            let count = MENU_ITEMS.len() as i16;
+            menu.selected = (menu.selected as i16 + step as i16).rem_euclid(count) as usize;
+        }
+    }
+}
+
+fn change(state: &mut State, item: MenuItem, step: i8) {
+    match item {
+        // The snippet layer isn't part of the cycle, so leave from the layer under it
+        MenuItem::Layer => {
+            let from = match state.layer {
+                Layer::Snippet => state.prev_layer,
+                layer => layer,
+            };
+            state.set_layer(if step > 0 { from.next() } else { from.prev() });
+        }
+        MenuItem::Brightness => state.step_brightness(step.saturating_mul(BRIGHTNESS_STEP)),
+        MenuItem::Layout => {
+            let layout = cycle(state.settings.layout as u8, step, HostLayout::from_u8);
+            if let Some(layout) = layout {
+                state.settings.layout = layout;
+                state.settings_dirty = true;
+            }
+        }
+        MenuItem::Unicode => {
+            let unicode = cycle(state.settings.unicode as u8, step, UnicodeMode::from_u8);
+            if let Some(unicode) = unicode {
+                state.settings.unicode = unicode;
+                state.settings_dirty = true;
+            }
+        }
+        // A custom speed set over serial steps on from NORMAL
+        MenuItem::Speed => {
+            let current = speed_index(state.settings.pacing).unwrap_or(1);
+            let next = (current as i16 + step as i16).rem_euclid(SPEEDS.len() as i16);
+            state.settings.pacing = SPEEDS[next as usize].1;
+            state.settings_dirty = true;
+        }
+    }
+}
+
+// Steps through the values `from_u8` accepts, wrapping at either end
+fn cycle<T>(current: u8, step: i8, from_u8: fn(u8) -> Option<T>) -> Option<T> {
+    let count = (0..=u8::MAX).take_while(|&n| from_u8(n).is_some()).count() as i16;
+    from_u8((current as i16 + step as i16).rem_euclid(count) as u8)
+}
+
+fn speed_index(pacing: Pacing) -> Option<usize> {
+    SPEEDS.iter().position(|&(_, speed)| speed == pacing)
+}
+
+// Display row for one item, e.g. "> LAYOUT   <QWERTY>" while it's changed
+pub fn line(state: &State, menu: &Menu, index: usize) -> String<MENU_LINE_LEN> {
+    let item = MENU_ITEMS[index];
+    let mut value: String<8> = String::new();
+    let _ = match item {
+        MenuItem::Layer => value.write_str(state.layer.name()),
+        MenuItem::Brightness => write!(value, "{}", state.settings.brightness),
+        MenuItem::Layout => value.write_str(state.settings.layout.name()),
+        MenuItem::Unicode => value.write_str(state.settings.unicode.name()),
+        MenuItem::Speed => value.write_str(match speed_index(state.settings.pacing) {
+            Some(index) => SPEEDS[index].0,
+            None => "CUSTOM",
+        }),
+    };
+    let selected = index == menu.selected;
+    let cursor = if selected { '>' } else { ' ' };
+    let mut line = String::new();
+    let _ = if selected && menu.editing {
+        write!(line, "{} {:<9}<{}>", cursor, item.name(), value)
+    } else {
+        write!(line, "{} {:<9}{}", cursor, item.name(), value)
+    };
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gesture::Gesture;
+    use crate::keymap::{KeyAction, LayerAction};
+    use crate::settings::Settings;
+
+    fn state() -> State {
+        State::new(Settings::defaults())
+    }
+
+    fn press(state: &mut State, gesture: Gesture) {
+        if let Some(KeyAction::Menu(action)) = state.button_action(gesture) {
+            run(state, action);
+        }
+    }
+
+    fn turn(state: &mut State, step: i8) {
+        if let KeyAction::Menu(action) = state.rotation(step) {
+            run(state, action);
+        }
+    }
+
+    #[test]
+    fn long_press_opens_and_closes() {
+        let mut state = state();
+        assert_eq!(state.rotation(1), KeyAction::Layer(LayerAction::Next));
+        press(&mut state, Gesture::LongPress);
+        assert_eq!(state.menu, Some(Menu::default()));
+        assert_eq!(state.rotation(1), KeyAction::Menu(MenuAction::Turn(1)));
+        press(&mut state, Gesture::LongPress);
+        assert!(state.menu.is_none());
+        assert_eq!(
+            state.button_action(Gesture::Tap),
+            Some(KeyAction::Layer(LayerAction::ToggleSnippet))
+        );
+    }
+
+    #[test]
+    fn turning_selects_then_changes() {
+        let mut state = state();
+        press(&mut state, Gesture::LongPress);
+        turn(&mut state, 2);
+        turn(&mut state, -1);
+        turn(&mut state, 1);
+        assert_eq!(MENU_ITEMS[state.menu.unwrap().selected], MenuItem::Layout);
+        assert_eq!(line(&state, &state.menu.unwrap(), 2), "> LAYOUT   COLEMAK");
+
+        press(&mut state, Gesture::Tap);
+        turn(&mut state, 1);
+        assert_eq!(state.settings.layout, HostLayout::Qwerty);
+        turn(&mut state, -2);
+        assert_eq!(state.settings.layout, HostLayout::German);
+        assert!(state.settings_dirty);
+        assert_eq!(line(&state, &state.menu.unwrap(), 2), "> LAYOUT   <DE>");
+
+        // Done changing, turning moves on again
+        press(&mut state, Gesture::Tap);
+        turn(&mut state, 1);
+        assert_eq!(MENU_ITEMS[state.menu.unwrap().selected], MenuItem::Unicode);
+        assert_eq!(state.settings.layout, HostLayout::German);
+    }
+
+    #[test]
+    fn layer_item_leaves_the_volume_dial() {
+        let mut state = state();
+        state.set_layer(Layer::Media);
+        state.toggle_snippet();
+        press(&mut state, Gesture::LongPress);
+        press(&mut state, Gesture::Tap);
+        turn(&mut state, 1);
+        assert_eq!(state.layer, Layer::Vibe);
+    }
+
+    #[test]
+    fn speed_steps_through_presets() {
+        let mut state = state();
+        state.settings.pacing = Pacing::parse(b"3:3", b':').unwrap();
+        let menu = Menu { selected: 4, editing: true };
+        state.menu = Some(menu);
+        assert_eq!(line(&state, &menu, 4), "> SPEED    <CUSTOM>");
+        run(&mut state, MenuAction::Turn(1));
+        assert_eq!(line(&state, &menu, 4), "> SPEED    <FAST>");
+        run(&mut state, MenuAction::Turn(1));
+        assert!(state.settings.pacing.rollover);
+        run(&mut state, MenuAction::Turn(1));
+        assert_eq!(state.settings.pacing, SPEEDS[0].1);
+        assert_eq!(line(&state, &menu, 1), "  BRIGHT   32");
+    }
+}
@@ -0,0 +1,780 @@
+use smart_leds::RGB8;
+
+use crate::keymap::{KeyAction, Layer};
+use crate::layout::{HostLayout, UnicodeMode};
+use crate::macros::{format_macro, parse_macro, Macro, MACRO_TEXT_LEN};
+use crate::scheduler::Pacing;
+use crate::state::{State, Status};
+use crate::{MESSAGE_LEN, NUM_LEDS, SNIPPET_COUNT};
+
+// Bumped whenever a command or reply format changes incompatibly
+pub const PROTOCOL_VERSION: &str = "1";
+const MAX_SEQ_LEN: usize = 8;
+// Longest line write_line sends, a MAC? reply for the longest macro:
+// "#<seq>:MAC:<key>:<chords>\r\n"
+pub const MAX_LINE_LEN: usize = MAX_SEQ_LEN + 2 + "MAC:11:".len() + MACRO_TEXT_LEN + 2;
+
+// Where replies and events go; the firmware writes them to the CDC port
+pub trait SerialOut {
+    fn write(&mut self, data: &[u8]);
+}
+
+pub fn parse_hex_color(s: &[u8]) -> Option<RGB8> {
+    if s.len() != 6 {
+        return None;
+    }
+    let r = hex_byte(&s[0..2])?;
+    let g = hex_byte(&s[2..4])?;
+    let b = hex_byte(&s[4..6])?;
+    Some(RGB8::new(r, g, b))
+}
+
+fn hex_byte(s: &[u8]) -> Option<u8> {
+    if s.len() != 2 {
+        return None;
+    }
+    let high = hex_digit(s[0])?;
+    let low = hex_digit(s[1])?;
+    Some((high << 4) | low)
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    }
+}
+
+pub fn parse_u8(s: &[u8]) -> Option<u8> {
+    if s.is_empty() || s.len() > 3 {
+        return None;
+    }
+    let mut n: u16 = 0;
+    for &c in s {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        n = n * 10 + (c - b'0') as u16;
+    }
+    u8::try_from(n).ok()
+}
+
+// Inverse of parse_u8
+pub fn format_u8(n: u8, buf: &mut [u8; 3]) -> &[u8] {
+    let mut start = buf.len();
+    let mut n = n;
+    loop {
+        start -= 1;
+        buf[start] = b'0' + n % 10;
+        n /= 10;
+        if n == 0 {
+            return &buf[start..];
+        }
+    }
+}
+
+// Inverse of parse_key_num: 0-based index to 1-based ASCII
+pub fn format_key_num(idx: usize, buf: &mut [u8; 2]) -> &[u8] {
+    let n = idx + 1;
+    if n >= 10 {
+        buf[0] = b'0' + (n / 10) as u8;
+        buf[1] = b'0' + (n % 10) as u8;
+        &buf[..]
+    } else {
+        buf[0] = b'0' + n as u8;
+        &buf[..1]
+    }
+}
+
+pub fn parse_key_num(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 2 {
+        return None;
+    }
+    let mut n: usize = 0;
+    for &c in s {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        n = n * 10 + (c - b'0') as usize;
+    }
+    if (1..=12).contains(&n) {
+        Some(n - 1)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CmdError {
+    Unknown,
+    Syntax,
+    BadKey,
+    BadValue,
+    TooLong,
+}
+
+impl CmdError {
+    pub fn code(self) -> &'static str {
+        match self {
+            CmdError::Unknown => "1",
+            CmdError::Syntax => "2",
+            CmdError::BadKey => "3",
+            CmdError::BadValue => "4",
+            CmdError::TooLong => "5",
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            CmdError::Unknown => "unknown command",
+            CmdError::Syntax => "malformed command",
+            CmdError::BadKey => "bad key",
+            CmdError::BadValue => "bad value",
+            CmdError::TooLong => "too long",
+        }
+    }
+}
+
+// Assembles a line so it reaches the host in one piece. A line that
+// wouldn't fit is sent as a "too long" error rather than cut short.
+pub fn write_line<O: SerialOut>(out: &mut O, seq: Option<&[u8]>, parts: &[&[u8]]) {
+    let too_long = CmdError::TooLong;
+    let error: [&[u8]; 4] =
+        [b"ERR:", too_long.code().as_bytes(), b":", too_long.reason().as_bytes()];
+    let len = seq.map_or(0, |seq| seq.len() + 2) + parts.iter().map(|p| p.len()).sum::<usize>();
+    let parts = if len + 2 > MAX_LINE_LEN { &error[..] } else { parts };
+
+    let mut line: heapless::Vec<u8, MAX_LINE_LEN> = heapless::Vec::new();
+    if let Some(seq) = seq {
+        let _ = line.push(b'#');
+        let _ = line.extend_from_slice(seq);
+        let _ = line.push(b':');
+    }
+    for part in parts {
+        let _ = line.extend_from_slice(part);
+    }
+    let _ = line.extend_from_slice(b"\r\n");
+    out.write(&line);
+}
+
+// Every reply line echoes the #<seq>: prefix of the command it answers
+pub struct Reply<'a, O: SerialOut> {
+    seq: Option<&'a [u8]>,
+    out: &'a mut O,
+}
+
+impl<O: SerialOut> Reply<'_, O> {
+    pub fn line(&mut self, parts: &[&[u8]]) {
+        write_line(self.out, self.seq, parts);
+    }
+
+    fn error(&mut self, err: CmdError) {
+        self.line(&[b"ERR:", err.code().as_bytes(), b":", err.reason().as_bytes()]);
+    }
+
+    fn snippet(&mut self, slot: usize, text: &str) {
+        let mut num = [0u8; 2];
+        let num = format_key_num(slot, &mut num);
+        self.line(&[b"SNP:", num, b":", text.as_bytes()]);
+    }
+
+    fn macro_steps(&mut self, slot: usize, steps: &Macro) -> Result<(), CmdError> {
+        let mut num = [0u8; 2];
+        let num = format_key_num(slot, &mut num);
+        let mut text: heapless::Vec<u8, MACRO_TEXT_LEN> = heapless::Vec::new();
+        format_macro(steps, &mut text).ok_or(CmdError::TooLong)?;
+        self.line(&[b"MAC:", num, b":", &text]);
+        Ok(())
+    }
+}
+
+// Unsolicited EVT: lines, only sent once the host opts in with EVT:ON
+pub fn send_event<O: SerialOut>(state: &State, out: &mut O, parts: &[&[u8]]) {
+    if state.events_enabled {
+        write_line(out, None, parts);
+    }
+}
+
+pub fn send_key_event<O: SerialOut>(state: &State, out: &mut O, key: usize, pressed: bool) {
+    let mut num = [0u8; 2];
+    let num = format_key_num(key, &mut num);
+    let edge: &[u8] = if pressed { b":DOWN:" } else { b":UP:" };
+    send_event(state, out, &[b"EVT:KEY:", num, edge, state.layer.name().as_bytes()]);
+}
+
+// Layers can change from keys, the encoder, the menu or the host; whichever
+// changed it calls this afterwards
+pub fn send_layer_event<O: SerialOut>(state: &mut State, out: &mut O) {
+    if state.layer != state.reported_layer {
+        state.reported_layer = state.layer;
+        send_event(state, out, &[b"EVT:LAYER:", state.layer.name().as_bytes()]);
+    }
+}
+
+// Splits an optional "#<seq>:" prefix off a command line
+fn split_seq(line: &[u8]) -> Result<(Option<&[u8]>, &[u8]), CmdError> {
+    if line.first() != Some(&b'#') {
+        return Ok((None, line));
+    }
+    let colon_pos = line.iter().position(|&c| c == b':').ok_or(CmdError::Syntax)?;
+    let seq = &line[1..colon_pos];
+    if seq.is_empty() || seq.len() > MAX_SEQ_LEN || !seq.iter().all(u8::is_ascii_alphanumeric) {
+        return Err(CmdError::Syntax);
+    }
+    Ok((Some(seq), &line[colon_pos + 1..]))
+}
+
+// Runs one received line and writes its replies. `overflowed` means the
+// line didn't fit the receive buffer and was truncated. `firmware_version`
+// is what VER? reports, the firmware crate's rather than this one's.
+pub fn handle_line<O: SerialOut>(
+    line: &[u8],
+    overflowed: bool,
+    firmware_version: &str,
+    state: &mut State,
+    out: &mut O,
+) {
+    let (seq, cmd) = match split_seq(line) {
+        Ok(parts) => parts,
+        Err(err) => return Reply { seq: None, out }.error(err),
+    };
+    let mut reply = Reply { seq, out };
+    let result = if overflowed {
+        Err(CmdError::TooLong)
+    } else {
+        process_command(cmd, firmware_version, state, &mut reply)
+    };
+    match result {
+        Ok(()) => reply.line(&[b"OK"]),
+        Err(err) => reply.error(err),
+    }
+    send_layer_event(state, out);
+}
+
+// Splits "<key>:<rest>" as used by RGB:, SNP: and MAC:
+fn split_key_arg(s: &[u8]) -> Result<(usize, &[u8]), CmdError> {
+    let colon_pos = s.iter().position(|&c| c == b':').ok_or(CmdError::Syntax)?;
+    let key_idx = parse_key_num(&s[..colon_pos]).ok_or(CmdError::BadKey)?;
+    Ok((key_idx, &s[colon_pos + 1..]))
+}
+
+fn snippet_slot(key_idx: usize) -> Result<usize, CmdError> {
+    if key_idx < SNIPPET_COUNT {
+        Ok(key_idx)
+    } else {
+        Err(CmdError::BadKey)
+    }
+}
+
+// Queries write their data lines through `reply`; the caller sends the
+// final OK or ERR line
+pub fn process_command<O: SerialOut>(
+    cmd: &[u8],
+    firmware_version: &str,
+    state: &mut State,
+    reply: &mut Reply<O>,
+) -> Result<(), CmdError> {
+    // PING
+    if cmd == b"PING" {
+        reply.line(&[b"PONG"]);
+        return Ok(());
+    }
+
+    // VER? -> VER:<firmware>:<protocol>
+    if cmd == b"VER?" {
+        reply.line(&[
+            b"VER:",
+            firmware_version.as_bytes(),
+            b":",
+            PROTOCOL_VERSION.as_bytes(),
+        ]);
+        return Ok(());
+    }
+
+    // MSG:<text>
+    if cmd.starts_with(b"MSG:") {
+        state.settings.message.clear();
+        let text = &cmd[4..];
+        for &c in text.iter().take(MESSAGE_LEN) {
+            if (0x20..0x7F).contains(&c) {
+                let _ = state.settings.message.push(c as char);
+            }
+        }
+        state.display_dirty = true;
+        state.settings_dirty = true;
+        return Ok(());
+    }
+
+    // STS:<state>
+    if cmd.starts_with(b"STS:") {
+        state.status = match &cmd[4..] {
+            b"IDLE" => Status::Idle,
+            b"RUN" => Status::Run,
+            b"WAIT" => Status::Wait,
+            b"ERR" => Status::Err,
+            _ => return Err(CmdError::BadValue),
+        };
+        state.display_dirty = true;
+        return Ok(());
+    }
+
+    // RGB:<key>:<hex>
+    if cmd.starts_with(b"RGB:") {
+        let (key_idx, hex) = split_key_arg(&cmd[4..])?;
+        let color = parse_hex_color(hex).ok_or(CmdError::BadValue)?;
+        let colors = state.settings.colors.get_or_insert([RGB8::default(); NUM_LEDS]);
+        colors[key_idx] = color;
+        state.settings_dirty = true;
+        return Ok(());
+    }
+
+    // BRI:<0-255>
+    if cmd.starts_with(b"BRI:") {
+        state.settings.brightness = parse_u8(&cmd[4..]).ok_or(CmdError::BadValue)?;
+        state.settings_dirty = true;
+        return Ok(());
+    }
+
+    // LAY:<name> sets the host layout snippets are typed for
+    if cmd.starts_with(b"LAY:") {
+        state.settings.layout = HostLayout::from_name(&cmd[4..]).ok_or(CmdError::BadValue)?;
+        state.settings_dirty = true;
+        return Ok(());
+    }
+
+    // LAY? -> LAY:<name>
+    if cmd == b"LAY?" {
+        reply.line(&[b"LAY:", state.settings.layout.name().as_bytes()]);
+        return Ok(());
+    }
+
+    // UNI:<mode> sets how chars the layout can't type are entered
+    if cmd.starts_with(b"UNI:") {
+        state.settings.unicode = UnicodeMode::from_name(&cmd[4..]).ok_or(CmdError::BadValue)?;
+        state.settings_dirty = true;
+        return Ok(());
+    }
+
+    // UNI? -> UNI:<mode>
+    if cmd == b"UNI?" {
+        reply.line(&[b"UNI:", state.settings.unicode.name().as_bytes()]);
+        return Ok(());
+    }
+
+    // SPD:<hold>:<gap>[:NKRO] sets how fast snippets are typed
+    if cmd.starts_with(b"SPD:") {
+        state.settings.pacing = Pacing::parse(&cmd[4..], b':').ok_or(CmdError::BadValue)?;
+        state.settings_dirty = true;
+        return Ok(());
+    }
+
+    // SPD? -> SPD:<hold>:<gap>[:NKRO]
+    if cmd == b"SPD?" {
+        let pacing = state.settings.pacing;
+        let (mut hold, mut gap) = ([0u8; 3], [0u8; 3]);
+        let rollover: &[u8] = if pacing.rollover { b":NKRO" } else { b"" };
+        reply.line(&[
+            b"SPD:",
+            format_u8(pacing.hold_ms, &mut hold),
+            b":",
+            format_u8(pacing.gap_ms, &mut gap),
+            rollover,
+        ]);
+        return Ok(());
+    }
+
+    // LYR:<name> switches layer; SNIPPET is entered like the SNIP key, so
+    // leaving it returns to the layer before
+    if cmd.starts_with(b"LYR:") {
+        match Layer::from_name(&cmd[4..]).ok_or(CmdError::BadValue)? {
+            Layer::Snippet if state.layer == Layer::Snippet => {}
+            Layer::Snippet => state.toggle_snippet(),
+            layer => state.set_layer(layer),
+        }
+        return Ok(());
+    }
+
+    // LYR? -> LYR:<name>
+    if cmd == b"LYR?" {
+        reply.line(&[b"LYR:", state.layer.name().as_bytes()]);
+        return Ok(());
+    }
+
+    // SNP:<key>:<text> (empty text clears the slot)
+    if cmd.starts_with(b"SNP:") {
+        let (key_idx, text) = split_key_arg(&cmd[4..])?;
+        let slot = snippet_slot(key_idx)?;
+        let text = core::str::from_utf8(text).map_err(|_| CmdError::BadValue)?;
+        let snippet = heapless::String::try_from(text).map_err(|_| CmdError::TooLong)?;
+        state.settings.snippets[slot] = snippet;
+        state.display_dirty = true;
+        state.settings_dirty = true;
+        return Ok(());
+    }
+
+    // SNP?:<key> reads back one snippet, bare SNP? dumps all of them
+    if cmd.starts_with(b"SNP?") {
+        match &cmd[4..] {
+            [] => {
+                for (slot, text) in state.settings.snippets.iter().enumerate() {
+                    reply.snippet(slot, text);
+                }
+            }
+            [b':', key @ ..] => {
+                let key_idx = parse_key_num(key).ok_or(CmdError::BadKey)?;
+                let slot = snippet_slot(key_idx)?;
+                reply.snippet(slot, &state.settings.snippets[slot]);
+            }
+            _ => return Err(CmdError::Syntax),
+        }
+        return Ok(());
+    }
+
+    // MAC:<key>:<chords> stores a macro in a snippet slot, e.g.
+    // MAC:3:GUI+C,TAB,GUI+V (empty chords clear it)
+    if cmd.starts_with(b"MAC:") {
+        let (key_idx, text) = split_key_arg(&cmd[4..])?;
+        let slot = snippet_slot(key_idx)?;
+        state.settings.macros[slot] = parse_macro(text).ok_or(CmdError::BadValue)?;
+        state.display_dirty = true;
+        state.settings_dirty = true;
+        return Ok(());
+    }
+
+    // MAC?:<key> reads back one macro, bare MAC? dumps all of them
+    if cmd.starts_with(b"MAC?") {
+        match &cmd[4..] {
+            [] => {
+                for (slot, steps) in state.settings.macros.iter().enumerate() {
+                    reply.macro_steps(slot, steps)?;
+                }
+            }
+            [b':', key @ ..] => {
+                let key_idx = parse_key_num(key).ok_or(CmdError::BadKey)?;
+                let slot = snippet_slot(key_idx)?;
+                reply.macro_steps(slot, &state.settings.macros[slot])?;
+            }
+            _ => return Err(CmdError::Syntax),
+        }
+        return Ok(());
+    }
+
+    // RUN:<key> types a snippet, or plays a macro, as if its key was pressed
+    if cmd.starts_with(b"RUN:") {
+        let key_idx = parse_key_num(&cmd[4..]).ok_or(CmdError::BadKey)?;
+        let slot = snippet_slot(key_idx)?;
+        state.host_action = Some(KeyAction::Snippet(slot));
+        return Ok(());
+    }
+
+    // EVT:ON / EVT:OFF
+    if cmd.starts_with(b"EVT:") {
+        state.events_enabled = match &cmd[4..] {
+            b"ON" => true,
+            b"OFF" => false,
+            _ => return Err(CmdError::BadValue),
+        };
+        return Ok(());
+    }
+
+    // CLR:
+    if cmd.starts_with(b"CLR:") {
+        state.settings.message.clear();
+        state.display_dirty = true;
+        state.settings_dirty = true;
+        return Ok(());
+    }
+
+    // RST:
+    if cmd.starts_with(b"RST:") {
+        state.reset();
+        return Ok(());
+    }
+
+    Err(CmdError::Unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macros::Chord;
+    use crate::settings::Settings;
+    use usbd_human_interface_device::page::Keyboard;
+
+    impl SerialOut for Vec<u8> {
+        fn write(&mut self, data: &[u8]) {
+            self.extend_from_slice(data);
+        }
+    }
+
+    // Runs one command line and returns the reply lines
+    fn run(state: &mut State, line: &str) -> Vec<String> {
+        let mut out = Vec::new();
+        handle_line(line.as_bytes(), false, "1.2.3", state, &mut out);
+        String::from_utf8(out)
+            .unwrap()
+            .split_terminator("\r\n")
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn state() -> State {
+        State::new(Settings::defaults())
+    }
+
+    #[test]
+    fn hex_colors() {
+        assert_eq!(parse_hex_color(b"ff8000"), Some(RGB8::new(255, 128, 0)));
+        assert_eq!(parse_hex_color(b"FF8000"), Some(RGB8::new(255, 128, 0)));
+        assert_eq!(parse_hex_color(b"zzzzzz"), None);
+        assert_eq!(parse_hex_color(b"fff"), None);
+    }
+
+    #[test]
+    fn key_numbers_are_one_based() {
+        assert_eq!(parse_key_num(b"1"), Some(0));
+        assert_eq!(parse_key_num(b"12"), Some(11));
+        assert_eq!(parse_key_num(b"0"), None);
+        assert_eq!(parse_key_num(b"13"), None);
+        assert_eq!(parse_key_num(b""), None);
+        let mut buf = [0u8; 2];
+        assert_eq!(format_key_num(10, &mut buf), b"11");
+    }
+
+    #[test]
+    fn ping_and_version() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "PING"), ["PONG", "OK"]);
+        let ver = format!("VER:1.2.3:{PROTOCOL_VERSION}");
+        assert_eq!(run(&mut state, "VER?"), [ver.as_str(), "OK"]);
+    }
+
+    #[test]
+    fn sequence_ids_are_echoed() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "#42:PING"), ["#42:PONG", "#42:OK"]);
+        assert_eq!(run(&mut state, "#a1:BOGUS"), ["#a1:ERR:1:unknown command"]);
+        assert_eq!(run(&mut state, "#:PING"), ["ERR:2:malformed command"]);
+        assert_eq!(run(&mut state, "#123456789:PING"), ["ERR:2:malformed command"]);
+    }
+
+    #[test]
+    fn rgb_sets_one_key() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "RGB:3:00ff00"), ["OK"]);
+        let colors = state.settings.colors.unwrap();
+        assert_eq!(colors[2], RGB8::new(0, 255, 0));
+        assert_eq!(colors[0], RGB8::default());
+        assert!(state.settings_dirty);
+    }
+
+    #[test]
+    fn rgb_rejections() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "RGB:13:ff0000"), ["ERR:3:bad key"]);
+        assert_eq!(run(&mut state, "RGB:12:zzzzzz"), ["ERR:4:bad value"]);
+        assert_eq!(run(&mut state, "RGB:12"), ["ERR:2:malformed command"]);
+        assert!(state.settings.colors.is_none());
+    }
+
+    #[test]
+    fn message_is_filtered_and_truncated() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "MSG:tests \x01passing on main branch"), ["OK"]);
+        assert_eq!(state.settings.message.as_str(), "tests passing on ma");
+        assert_eq!(run(&mut state, "CLR:"), ["OK"]);
+        assert!(state.settings.message.is_empty());
+    }
+
+    #[test]
+    fn status_values() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "STS:RUN"), ["OK"]);
+        assert_eq!(state.status, Status::Run);
+        assert_eq!(run(&mut state, "STS:BUSY"), ["ERR:4:bad value"]);
+        assert_eq!(state.status, Status::Run);
+    }
+
+    #[test]
+    fn brightness() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "BRI:255"), ["OK"]);
+        assert_eq!(state.settings.brightness, 255);
+        assert_eq!(run(&mut state, "BRI:256"), ["ERR:4:bad value"]);
+    }
+
+    #[test]
+    fn host_layout() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "LAY?"), ["LAY:COLEMAK", "OK"]);
+        assert_eq!(run(&mut state, "LAY:DVORAK"), ["OK"]);
+        assert_eq!(state.settings.layout, HostLayout::Dvorak);
+        assert!(state.settings_dirty);
+        assert_eq!(run(&mut state, "LAY?"), ["LAY:DVORAK", "OK"]);
+        assert_eq!(run(&mut state, "LAY:AZERTY"), ["ERR:4:bad value"]);
+    }
+
+    #[test]
+    fn typing_speed() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "SPD?"), ["SPD:30:20", "OK"]);
+        assert_eq!(run(&mut state, "SPD:5:120"), ["OK"]);
+        assert!(state.settings_dirty);
+        assert_eq!(run(&mut state, "SPD?"), ["SPD:5:120", "OK"]);
+        assert_eq!(run(&mut state, "SPD:1:1:NKRO"), ["OK"]);
+        assert!(state.settings.pacing.rollover);
+        assert_eq!(run(&mut state, "SPD?"), ["SPD:1:1:NKRO", "OK"]);
+        assert_eq!(run(&mut state, "SPD:0:20"), ["ERR:4:bad value"]);
+        assert_eq!(run(&mut state, "SPD:30"), ["ERR:4:bad value"]);
+        assert_eq!(run(&mut state, "SPD:30:20:FAST"), ["ERR:4:bad value"]);
+    }
+
+    #[test]
+    fn layer_switch() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "LYR:MEDIA"), ["OK"]);
+        assert_eq!(state.settings.layer, Layer::Media);
+        assert_eq!(run(&mut state, "LYR:SNIPPET"), ["OK"]);
+        assert_eq!(run(&mut state, "LYR:SNIPPET"), ["OK"]);
+        assert_eq!(run(&mut state, "LYR?"), ["LYR:SNIPPET", "OK"]);
+        assert_eq!(state.prev_layer, Layer::Media);
+        assert_eq!(run(&mut state, "LYR:VIBE"), ["OK"]);
+        assert_eq!(run(&mut state, "LYR?"), ["LYR:VIBE", "OK"]);
+        assert_eq!(run(&mut state, "LYR:FN"), ["ERR:4:bad value"]);
+    }
+
+    #[test]
+    fn unicode_mode() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "UNI?"), ["UNI:OFF", "OK"]);
+        assert_eq!(run(&mut state, "UNI:LINUX"), ["OK"]);
+        assert_eq!(state.settings.unicode, UnicodeMode::Linux);
+        assert!(state.settings_dirty);
+        assert_eq!(run(&mut state, "UNI?"), ["UNI:LINUX", "OK"]);
+        assert_eq!(run(&mut state, "UNI:BEOS"), ["ERR:4:bad value"]);
+    }
+
+    #[test]
+    fn snippets_set_and_read_back() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "SNP:4:git push"), ["OK"]);
+        assert_eq!(run(&mut state, "SNP?:4"), ["SNP:4:git push", "OK"]);
+        assert_eq!(run(&mut state, "SNP:12:nope"), ["ERR:3:bad key"]);
+        assert_eq!(run(&mut state, "SNP?:0"), ["ERR:3:bad key"]);
+        let all = run(&mut state, "SNP?");
+        assert_eq!(all.len(), SNIPPET_COUNT + 1);
+        assert_eq!(all[0], "SNP:1:!td");
+        assert_eq!(all[3], "SNP:4:git push");
+    }
+
+    #[test]
+    fn oversized_snippet_keeps_old_text() {
+        let mut state = state();
+        let long = format!("SNP:1:{}", "x".repeat(crate::SNIPPET_LEN + 1));
+        assert_eq!(run(&mut state, &long), ["ERR:5:too long"]);
+        assert_eq!(state.settings.snippets[0].as_str(), "!td");
+    }
+
+    #[test]
+    fn macros_set_and_read_back() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "MAC:3:gui+c,TAB,GUI+V"), ["OK"]);
+        assert_eq!(state.settings.macros[2].len(), 3);
+        assert!(state.settings_dirty);
+        assert_eq!(run(&mut state, "MAC?:3"), ["MAC:3:GUI+C,TAB,GUI+V", "OK"]);
+        assert_eq!(run(&mut state, "MAC?:1"), ["MAC:1:", "OK"]);
+        assert_eq!(run(&mut state, "MAC?").len(), SNIPPET_COUNT + 1);
+        assert_eq!(run(&mut state, "MAC:3:GUI+NOPE"), ["ERR:4:bad value"]);
+        assert_eq!(state.settings.macros[2].len(), 3);
+        assert_eq!(run(&mut state, "MAC:12:A"), ["ERR:3:bad key"]);
+        assert_eq!(run(&mut state, "MAC:3:"), ["OK"]);
+        assert!(state.settings.macros[2].is_empty());
+    }
+
+    #[test]
+    fn longest_macro_reads_back() {
+        let mut state = state();
+        let keys = [
+            Keyboard::RightShift,
+            Keyboard::RightControl,
+            Keyboard::LeftShift,
+            Keyboard::ReturnEnter,
+            Keyboard::Minus,
+            Keyboard::Equal,
+        ];
+        let chord = Chord::from_slice(&keys).unwrap();
+        for steps in state.settings.macros.iter_mut() {
+            while steps.push(chord.clone()).is_ok() {}
+        }
+        let reply = run(&mut state, "#12345678:MAC?:11");
+        assert_eq!(reply.len(), 2);
+        let text = reply[0].strip_prefix("#12345678:MAC:11:").unwrap();
+        assert_eq!(parse_macro(text.as_bytes()).unwrap(), state.settings.macros[10]);
+        assert_eq!(run(&mut state, "MAC?").len(), SNIPPET_COUNT + 1);
+    }
+
+    #[test]
+    fn overlong_lines_become_errors() {
+        let mut out = Vec::new();
+        write_line(&mut out, Some(b"7"), &[b"MSG:", &[b'x'; MAX_LINE_LEN]]);
+        assert_eq!(out, b"#7:ERR:5:too long\r\n");
+    }
+
+    #[test]
+    fn truncated_lines_are_rejected() {
+        let mut state = state();
+        let mut out = Vec::new();
+        handle_line(b"#9:MSG:abc", true, "1.2.3", &mut state, &mut out);
+        assert_eq!(out, b"#9:ERR:5:too long\r\n");
+        assert!(state.settings.message.is_empty());
+    }
+
+    #[test]
+    fn reset_restores_defaults() {
+        let mut state = state();
+        run(&mut state, "RGB:1:ffffff");
+        run(&mut state, "MSG:hello");
+        assert_eq!(run(&mut state, "RST:"), ["OK"]);
+        assert!(state.settings.colors.is_none());
+        assert!(state.settings.message.is_empty());
+    }
+
+    #[test]
+    fn run_queues_a_snippet() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "RUN:4"), ["OK"]);
+        assert_eq!(state.host_action, Some(KeyAction::Snippet(3)));
+        assert_eq!(run(&mut state, "RUN:12"), ["ERR:3:bad key"]);
+    }
+
+    #[test]
+    fn events_only_flow_when_enabled() {
+        let mut state = state();
+        let mut out = Vec::new();
+        send_key_event(&state, &mut out, 0, true);
+        assert!(out.is_empty());
+
+        assert_eq!(run(&mut state, "EVT:ON"), ["OK"]);
+        state.set_layer(Layer::Media);
+        send_layer_event(&mut state, &mut out);
+        send_key_event(&state, &mut out, 10, true);
+        send_key_event(&state, &mut out, 10, false);
+        assert_eq!(
+            out,
+            b"EVT:LAYER:MEDIA\r\nEVT:KEY:11:DOWN:MEDIA\r\nEVT:KEY:11:UP:MEDIA\r\n"
+        );
+
+        assert_eq!(run(&mut state, "EVT:MAYBE"), ["ERR:4:bad value"]);
+    }
+
+    #[test]
+    fn host_layer_switch_sends_event() {
+        let mut state = state();
+        assert_eq!(run(&mut state, "LYR:MEDIA"), ["OK"]);
+        assert_eq!(run(&mut state, "EVT:ON"), ["OK"]);
+        assert_eq!(run(&mut state, "#7:LYR:VIBE"), ["#7:OK", "EVT:LAYER:VIBE"]);
+        assert_eq!(run(&mut state, "LYR:VIBE"), ["OK"]);
+    }
+}
@@ -0,0 +1,868 @@
+use heapless::{Deque, String, Vec};
+use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+use crate::keymap::{KeyAction, LayerAction};
+use crate::layout::{HostLayout, KeyStroke, UnicodeMode};
+use crate::macros::{next_token, Chord, Macro, Token};
+use crate::menu;
+use crate::protocol::{format_key_num, parse_u8, send_event, SerialOut};
+use crate::state::State;
+use crate::SNIPPET_LEN;
+
+// Shortcut chords and consumer keys are held this long
+const CHORD_HOLD_MS: u32 = 50;
+// Gap between the taps of a Taps action
+const TAP_GAP_MS: u32 = 50;
+// Minimum spacing between reports, so each gets its own USB poll
+const REPORT_GAP_MS: u32 = 10;
+
+pub const MAX_REPORT_KEYS: usize = 6;
+// Actions queued behind the one running; further actions are dropped
+const JOB_QUEUE_LEN: usize = 8;
+
+// One HID report for the firmware to send
+#[derive(Clone, PartialEq, Debug)]
+pub enum Report {
+    Keys(Vec<Keyboard, MAX_REPORT_KEYS>),
+    Consumer(Option<Consumer>),
+    Scroll(i8),
+}
+
+impl Report {
+    fn keys(keys: &[Keyboard]) -> Self {
+        Report::Keys(keys.iter().copied().take(MAX_REPORT_KEYS).collect())
+    }
+
+    // `keys` pressed on top of the `held` ones
+    fn over(held: &[Keyboard], keys: &[Keyboard]) -> Self {
+        let mut all = Vec::new();
+        for &key in held.iter().chain(keys) {
+            if !all.contains(&key) {
+                let _ = all.push(key);
+            }
+        }
+        Report::Keys(all)
+    }
+
+    fn stroke(held: &[Keyboard], stroke: KeyStroke) -> Self {
+        let mut keys: Vec<Keyboard, 3> = Vec::new();
+        if stroke.shift {
+            let _ = keys.push(Keyboard::LeftShift);
+        }
+        if stroke.altgr {
+            let _ = keys.push(Keyboard::RightAlt);
+        }
+        let _ = keys.push(stroke.key);
+        Self::over(held, &keys)
+    }
+
+    fn release() -> Self {
+        Report::Keys(Vec::new())
+    }
+}
+
+// How snippet text is typed: each char is held for `hold_ms`, then let go
+// for `gap_ms` before the next. With `rollover`, runs of different keys
+// are instead pressed one on top of another and let go together, so most
+// chars take a single report; a repeated key, a change of modifiers or a
+// full report starts a new run.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Pacing {
+    pub hold_ms: u8,
+    pub gap_ms: u8,
+    pub rollover: bool,
+}
+
+impl Pacing {
+    pub const DEFAULT: Pacing = Pacing {
+        hold_ms: 30,
+        gap_ms: 20,
+        rollover: false,
+    };
+
+    // Times are 1-255 ms, so every report gets its own USB poll
+    pub fn new(hold_ms: u8, gap_ms: u8, rollover: bool) -> Option<Self> {
+        (hold_ms > 0 && gap_ms > 0).then_some(Self {
+            hold_ms,
+            gap_ms,
+            rollover,
+        })
+    }
+
+    // "<hold>:<gap>" or "<hold>:<gap>:NKRO" with `sep` between the parts
+    pub fn parse(text: &[u8], sep: u8) -> Option<Self> {
+        let mut parts = text.split(|&c| c == sep);
+        let hold_ms = parse_u8(parts.next()?)?;
+        let gap_ms = parse_u8(parts.next()?)?;
+        let rollover = match parts.next() {
+            None => false,
+            Some(flag) if flag.eq_ignore_ascii_case(b"NKRO") => true,
+            Some(_) => return None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Self::new(hold_ms, gap_ms, rollover)
+    }
+
+    fn hold(self) -> u32 {
+        self.hold_ms as u32
+    }
+
+    fn gap(self) -> u32 {
+        self.gap_ms as u32
+    }
+}
+
+// A report plus how long to wait before the next one
+type Step = (Report, u32);
+
+// Reports of the chunk being sent; the most any job queues at once is
+// ending a rollover run, one char entered by code point, then letting go
+// of held keys
+type Steps = Deque<Step, 20>;
+
+// A queued HID action, expanded into reports a chunk at a time
+enum Job {
+    Chord(&'static [Keyboard]),
+    // A typematic repeat, played like a chord
+    Repeat(&'static [Keyboard]),
+    Taps(&'static [&'static [Keyboard]], usize),
+    Text {
+        text: String<SNIPPET_LEN>,
+        layout: HostLayout,
+        unicode: UnicodeMode,
+        pacing: Pacing,
+        pos: usize,
+        // Keys down from a {HOLD} escape
+        held: Chord,
+        // Keys down in the current rollover run, on top of `held`
+        run: Chord,
+    },
+    Macro(Macro, usize),
+    Consumer(Consumer),
+    Scroll(i8),
+}
+
+impl Job {
+    // Queue the next chunk of reports, returning true once the job is done
+    fn refill(&mut self, steps: &mut Steps) -> bool {
+        match self {
+            Job::Chord(keys) | Job::Repeat(keys) => {
+                let _ = steps.push_back((Report::keys(keys), CHORD_HOLD_MS));
+                let _ = steps.push_back((Report::release(), REPORT_GAP_MS));
+                true
+            }
+            Job::Taps(taps, next) => {
+                let Some(keys) = taps.get(*next) else {
+                    return true;
+                };
+                let _ = steps.push_back((Report::keys(keys), CHORD_HOLD_MS));
+                let _ = steps.push_back((Report::release(), TAP_GAP_MS));
+                *next += 1;
+                *next == taps.len()
+            }
+            Job::Text {
+                text,
+                layout,
+                unicode,
+                pacing,
+                pos,
+                held,
+                run,
+            } => {
+                // Queue the next token that sends anything
+                while let Some((token, rest)) = next_token(&text[*pos..]) {
+                    *pos = text.len() - rest.len();
+                    // Only plain chars can join a rollover run
+                    if !matches!(token, Token::Char(_) | Token::Speed(_)) {
+                        end_run(steps, held, run, *pacing);
+                    }
+                    let up = Report::keys(held);
+                    match token {
+                        Token::Char(c) => {
+                            let Some(stroke) = layout.key_for(c) else {
+                                // Without a Unicode method the char is skipped
+                                if *unicode == UnicodeMode::Off {
+                                    continue;
+                                }
+                                end_run(steps, held, run, *pacing);
+                                push_unicode(steps, *unicode, *layout, *pacing, c);
+                                break;
+                            };
+                            // Dead keys need their own release and Space
+                            if pacing.rollover && !stroke.dead {
+                                roll(steps, held, run, *pacing, stroke);
+                                break;
+                            }
+                            end_run(steps, held, run, *pacing);
+                            let _ = steps.push_back((Report::stroke(held, stroke), pacing.hold()));
+                            let _ = steps.push_back((up.clone(), pacing.gap()));
+                            // Dead keys wait for a second key; Space makes them type themselves
+                            if stroke.dead {
+                                let space = Report::over(held, &[Keyboard::Space]);
+                                let _ = steps.push_back((space, pacing.hold()));
+                                let _ = steps.push_back((up, pacing.gap()));
+                            }
+                        }
+                        Token::Chord(keys) => {
+                            let _ = steps.push_back((Report::over(held, &keys), CHORD_HOLD_MS));
+                            let _ = steps.push_back((up, TAP_GAP_MS));
+                        }
+                        // Nothing changes, the current keys just stay put
+                        Token::Delay(ms) => {
+                            let _ = steps.push_back((up, ms));
+                        }
+                        Token::Hold(keys) => {
+                            for key in keys {
+                                if !held.contains(&key) {
+                                    let _ = held.push(key);
+                                }
+                            }
+                            let _ = steps.push_back((Report::keys(held), TAP_GAP_MS));
+                        }
+                        Token::Release => {
+                            held.clear();
+                            let _ = steps.push_back((Report::release(), TAP_GAP_MS));
+                        }
+                        // Applies from here to the end of the snippet
+                        Token::Speed(speed) => {
+                            *pacing = speed;
+                            continue;
+                        }
+                    }
+                    break;
+                }
+                // Never leave keys down once the snippet is done
+                let done = *pos == text.len();
+                if done {
+                    end_run(steps, held, run, *pacing);
+                }
+                if done && !held.is_empty() {
+                    held.clear();
+                    let _ = steps.push_back((Report::release(), REPORT_GAP_MS));
+                }
+                done
+            }
+            Job::Macro(chords, next) => {
+                let Some(keys) = chords.get(*next) else {
+                    return true;
+                };
+                let _ = steps.push_back((Report::keys(keys), CHORD_HOLD_MS));
+                let _ = steps.push_back((Report::release(), TAP_GAP_MS));
+                *next += 1;
+                *next == chords.len()
+            }
+            Job::Consumer(code) => {
+                let _ = steps.push_back((Report::Consumer(Some(*code)), CHORD_HOLD_MS));
+                let _ = steps.push_back((Report::Consumer(None), REPORT_GAP_MS));
+                true
+            }
+            // Wheel movement is relative, so one report per scroll is enough
+            Job::Scroll(notches) => {
+                let _ = steps.push_back((Report::Scroll(*notches), REPORT_GAP_MS));
+                true
+            }
+        }
+    }
+}
+
+// Presses `stroke` on top of the current run, first letting the run go if
+// the key is already down, the modifiers differ or the report is full.
+// Each press is its own report so the host sees the chars in order.
+fn roll(steps: &mut Steps, held: &[Keyboard], run: &mut Chord, pacing: Pacing, stroke: KeyStroke) {
+    let joins = stroke.shift == run.contains(&Keyboard::LeftShift)
+        && stroke.altgr == run.contains(&Keyboard::RightAlt)
+        && !run.contains(&stroke.key)
+        && held.len() + run.len() < MAX_REPORT_KEYS;
+    if !joins {
+        end_run(steps, held, run, pacing);
+    }
+    if run.is_empty() {
+        if stroke.shift {
+            let _ = run.push(Keyboard::LeftShift);
+        }
+        if stroke.altgr {
+            let _ = run.push(Keyboard::RightAlt);
+        }
+    }
+    let _ = run.push(stroke.key);
+    let _ = steps.push_back((Report::over(held, run), pacing.hold()));
+}
+
+// Lets go of the keys in the current run, if any
+fn end_run(steps: &mut Steps, held: &[Keyboard], run: &mut Chord, pacing: Pacing) {
+    if !run.is_empty() {
+        run.clear();
+        let _ = steps.push_back((Report::keys(held), pacing.gap()));
+    }
+}
+
+// Taps `keys` with `held` down around them
+fn tap(steps: &mut Steps, held: &[Keyboard], keys: &[Keyboard], pacing: Pacing) {
+    let _ = steps.push_back((Report::over(held, keys), pacing.hold()));
+    let _ = steps.push_back((Report::keys(held), pacing.gap()));
+}
+
+// Lowercase hex of `value`, zero-padded to at least `min_len` digits
+fn hex(value: u32, min_len: usize, buf: &mut [u8; 8]) -> &[u8] {
+    let mut len = min_len;
+    while len < 8 && value >> (4 * len) != 0 {
+        len += 1;
+    }
+    for (i, digit) in buf[..len].iter_mut().rev().enumerate() {
+        *digit = b"0123456789abcdef"[(value >> (4 * i) & 0xF) as usize];
+    }
+    &buf[..len]
+}
+
+// Enters `c` by code point. Any keys held by {HOLD} are let go first and
+// come back with the next char.
+fn push_unicode(
+    steps: &mut Steps,
+    mode: UnicodeMode,
+    layout: HostLayout,
+    pacing: Pacing,
+    c: char,
+) {
+    // Hex digits typed through the host layout
+    let digit_key = |digit: u8| layout.key_for(digit as char).map(|stroke| stroke.key);
+    let mut buf = [0u8; 8];
+    match mode {
+        UnicodeMode::Off => {}
+        // Unicode Hex Input is a US layout and takes UTF-16 units, so chars
+        // past U+FFFF go in as a surrogate pair
+        UnicodeMode::MacOs => {
+            let mut units = [0u16; 2];
+            for &unit in c.encode_utf16(&mut units).iter() {
+                for &digit in hex(unit as u32, 4, &mut buf) {
+                    if let Some(stroke) = HostLayout::Qwerty.key_for(digit as char) {
+                        tap(steps, &[Keyboard::LeftAlt], &[stroke.key], pacing);
+                    }
+                }
+            }
+            let _ = steps.push_back((Report::release(), pacing.gap()));
+        }
+        UnicodeMode::Linux => {
+            if let Some(u) = digit_key(b'u') {
+                tap(steps, &[], &[Keyboard::LeftControl, Keyboard::LeftShift, u], pacing);
+            }
+            for &digit in hex(c as u32, 1, &mut buf) {
+                if let Some(key) = digit_key(digit) {
+                    tap(steps, &[], &[key], pacing);
+                }
+            }
+            tap(steps, &[], &[Keyboard::Space], pacing);
+        }
+        // Digits come from the keypad, which doesn't depend on the layout
+        UnicodeMode::Windows => {
+            let _ = steps.push_back((Report::keys(&[Keyboard::LeftAlt]), pacing.gap()));
+            tap(steps, &[Keyboard::LeftAlt], &[Keyboard::KeypadAdd], pacing);
+            for &digit in hex(c as u32, 1, &mut buf) {
+                let key = match digit {
+                    b'0' => Some(Keyboard::Keypad0),
+                    b'1'..=b'9' => Some(Keyboard::from(u8::from(Keyboard::Keypad1) + digit - b'1')),
+                    _ => digit_key(digit),
+                };
+                if let Some(key) = key {
+                    tap(steps, &[Keyboard::LeftAlt], &[key], pacing);
+                }
+            }
+            let _ = steps.push_back((Report::release(), pacing.gap()));
+        }
+    }
+}
+
+// Runs key actions without blocking: HID output is queued and handed back
+// one report at a time from `poll` as each one comes due
+pub struct Scheduler {
+    jobs: Deque<Job, JOB_QUEUE_LEN>,
+    steps: Steps,
+    // Keys of the repeat whose reports are in `steps`, if it's one
+    repeating: Option<&'static [Keyboard]>,
+    sent_at: u32,
+    wait_ms: u32,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            jobs: Deque::new(),
+            steps: Deque::new(),
+            repeating: None,
+            sent_at: 0,
+            wait_ms: 0,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.jobs.is_empty() && self.steps.is_empty()
+    }
+
+    // Queued behind any running actions; dropped if the queue is full
+    fn queue(&mut self, job: Job) {
+        let _ = self.jobs.push_back(job);
+    }
+
+    // State changes happen immediately; HID output joins the queue
+    pub fn run_action<O: SerialOut>(&mut self, action: KeyAction, state: &mut State, out: &mut O) {
+        match action {
+            KeyAction::Chord(keys) => {
+                state.record(keys);
+                self.queue(Job::Chord(keys));
+            }
+            // Repeats come in faster than a chord plays. Skipping them while
+            // the last one is still waiting or playing keeps a backlog from
+            // running on after the key is let go.
+            KeyAction::Repeat(keys) => {
+                let playing = !self.steps.is_empty() && self.repeating == Some(keys);
+                let queued = |job: &Job| matches!(job, Job::Repeat(queued) if *queued == keys);
+                if !playing && !self.jobs.iter().any(queued) {
+                    state.record(keys);
+                    self.queue(Job::Repeat(keys));
+                }
+            }
+            KeyAction::Taps(taps) => {
+                for keys in taps {
+                    state.record(keys);
+                }
+                self.queue(Job::Taps(taps, 0));
+            }
+            // Pressing a snippet key while recording picks where the macro goes
+            KeyAction::Snippet(slot) if state.recording.is_some() => {
+                state.save_recording(slot);
+                let mut num = [0u8; 2];
+                send_event(state, out, &[b"EVT:REC:SAVE:", format_key_num(slot, &mut num)]);
+            }
+            // Both copied so a host edit mid-type doesn't change what's typed
+            KeyAction::Snippet(slot) if !state.settings.macros[slot].is_empty() => {
+                self.queue(Job::Macro(state.settings.macros[slot].clone(), 0));
+            }
+            KeyAction::Snippet(slot) => self.queue(Job::Text {
+                text: state.settings.snippets[slot].clone(),
+                layout: state.settings.layout,
+                unicode: state.settings.unicode,
+                pacing: state.settings.pacing,
+                pos: 0,
+                held: Chord::new(),
+                run: Chord::new(),
+            }),
+            KeyAction::Consumer(code) => self.queue(Job::Consumer(code)),
+            KeyAction::Scroll(notches) => self.queue(Job::Scroll(notches)),
+            // Keys resolve these in HoldTracker; bound elsewhere they just tap
+            KeyAction::TapHold(tap, _) => self.run_action(*tap, state, out),
+            KeyAction::Layer(LayerAction::ToggleSnippet) => state.toggle_snippet(),
+            KeyAction::Layer(LayerAction::Next) => state.set_layer(state.layer.next()),
+            KeyAction::Layer(LayerAction::Prev) => state.set_layer(state.layer.prev()),
+            KeyAction::Brightness(step) => state.step_brightness(step),
+            KeyAction::Notify(line) => {
+                out.write(line.as_bytes());
+                out.write(b"\r\n");
+            }
+            KeyAction::Record if state.recording.is_some() => {
+                state.cancel_recording();
+                send_event(state, out, &[b"EVT:REC:CANCEL"]);
+            }
+            KeyAction::Record => {
+                state.start_recording();
+                send_event(state, out, &[b"EVT:REC:START"]);
+            }
+            KeyAction::Menu(action) => menu::run(state, action),
+        }
+    }
+
+    // Call every loop; returns the next report once the previous one has
+    // been held for its time
+    pub fn poll(&mut self, now_ms: u32) -> Option<Report> {
+        if now_ms.wrapping_sub(self.sent_at) < self.wait_ms {
+            return None;
+        }
+        while self.steps.is_empty() {
+            let job = self.jobs.front_mut()?;
+            self.repeating = match job {
+                Job::Repeat(keys) => Some(*keys),
+                _ => None,
+            };
+            if job.refill(&mut self.steps) {
+                self.jobs.pop_front();
+            }
+        }
+        let (report, wait_ms) = self.steps.pop_front()?;
+        self.sent_at = now_ms;
+        self.wait_ms = wait_ms;
+        Some(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use std::vec::Vec;
+
+    // A scheduler with the state and serial output it runs against, typing
+    // through a US layout unless a test picks another
+    struct Pad {
+        scheduler: Scheduler,
+        state: State,
+        out: Vec<u8>,
+    }
+
+    impl Pad {
+        fn new() -> Self {
+            let mut state = State::new(Settings::defaults());
+            state.settings.layout = HostLayout::Qwerty;
+            Self {
+                scheduler: Scheduler::new(),
+                state,
+                out: Vec::new(),
+            }
+        }
+
+        fn paced(pacing: &[u8]) -> Self {
+            let mut pad = Self::new();
+            pad.state.settings.pacing = Pacing::parse(pacing, b':').unwrap();
+            pad
+        }
+
+        fn unicode(unicode: UnicodeMode, layout: HostLayout) -> Self {
+            let mut pad = Self::new();
+            pad.state.settings.unicode = unicode;
+            pad.state.settings.layout = layout;
+            pad
+        }
+
+        fn run(&mut self, action: KeyAction) {
+            self.scheduler.run_action(action, &mut self.state, &mut self.out);
+        }
+
+        // Polls every ms and records (time, report) until the queue drains
+        fn drain(&mut self) -> Vec<(u32, Report)> {
+            let mut sent = Vec::new();
+            for now in 0..10_000 {
+                if let Some(report) = self.scheduler.poll(now) {
+                    sent.push((now, report));
+                }
+                if self.scheduler.is_idle() {
+                    break;
+                }
+            }
+            sent
+        }
+
+        // Types `text` from the first snippet slot
+        fn type_snippet(&mut self, text: &str) -> Vec<(u32, Report)> {
+            self.state.settings.snippets[0] = String::try_from(text).unwrap();
+            self.run(KeyAction::Snippet(0));
+            self.drain()
+        }
+    }
+
+    fn keys(keys: &[Keyboard]) -> Report {
+        Report::keys(keys)
+    }
+
+    // Just the reports, without when they were sent
+    fn reports(sent: Vec<(u32, Report)>) -> Vec<Report> {
+        sent.into_iter().map(|(_, report)| report).collect()
+    }
+
+    #[test]
+    fn chord_holds_then_releases() {
+        let mut pad = Pad::new();
+        pad.run(KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::C]));
+        assert_eq!(
+            pad.drain(),
+            [(0, keys(&[Keyboard::LeftGUI, Keyboard::C])), (50, Report::release())]
+        );
+    }
+
+    #[test]
+    fn taps_are_spaced() {
+        let mut pad = Pad::new();
+        pad.run(KeyAction::Taps(&[&[Keyboard::Escape], &[Keyboard::Escape]]));
+        let times: Vec<u32> = pad.drain().iter().map(|(t, _)| *t).collect();
+        assert_eq!(times, [0, 50, 100, 150]);
+    }
+
+    #[test]
+    fn snippet_types_one_char_at_a_time() {
+        let mut pad = Pad::new();
+        pad.state.settings.snippets[0] = String::try_from("Hé!").unwrap();
+        pad.run(KeyAction::Snippet(0));
+        // Host edits after the key press don't affect the typing
+        pad.state.settings.snippets[0].clear();
+        assert_eq!(
+            pad.drain(),
+            [
+                (0, keys(&[Keyboard::LeftShift, Keyboard::H])),
+                (30, Report::release()),
+                (50, keys(&[Keyboard::LeftShift, Keyboard::Keyboard1])),
+                (80, Report::release()),
+            ]
+        );
+    }
+
+    #[test]
+    fn dead_keys_are_followed_by_space() {
+        let mut pad = Pad::new();
+        pad.state.settings.layout = HostLayout::German;
+        assert_eq!(
+            reports(pad.type_snippet("^")),
+            [
+                keys(&[Keyboard::Grave]),
+                Report::release(),
+                keys(&[Keyboard::Space]),
+                Report::release(),
+            ]
+        );
+    }
+
+    #[test]
+    fn snippet_escapes() {
+        assert_eq!(
+            Pad::new().type_snippet("{GUI+SPACE}{DELAY 200}a{ENTER}"),
+            [
+                (0, keys(&[Keyboard::LeftGUI, Keyboard::Space])),
+                (50, Report::release()),
+                (100, Report::release()),
+                (300, keys(&[Keyboard::A])),
+                (330, Report::release()),
+                (350, keys(&[Keyboard::ReturnEnter])),
+                (400, Report::release()),
+            ]
+        );
+    }
+
+    #[test]
+    fn held_keys_stay_down_until_released() {
+        assert_eq!(
+            reports(Pad::new().type_snippet("{HOLD CTRL}a{RELEASE}b{HOLD ALT}")),
+            [
+                keys(&[Keyboard::LeftControl]),
+                keys(&[Keyboard::LeftControl, Keyboard::A]),
+                keys(&[Keyboard::LeftControl]),
+                Report::release(),
+                keys(&[Keyboard::B]),
+                Report::release(),
+                keys(&[Keyboard::LeftAlt]),
+                // Released at the end even without {RELEASE}
+                Report::release(),
+            ]
+        );
+    }
+
+    #[test]
+    fn pacing_comes_from_settings() {
+        assert_eq!(
+            Pad::paced(b"5:2").type_snippet("ab"),
+            [
+                (0, keys(&[Keyboard::A])),
+                (5, Report::release()),
+                (7, keys(&[Keyboard::B])),
+                (12, Report::release()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rollover_presses_runs_of_keys_in_order() {
+        let shift = Keyboard::LeftShift;
+        assert_eq!(
+            Pad::paced(b"1:1:NKRO").type_snippet("hello W"),
+            [
+                (0, keys(&[Keyboard::H])),
+                (1, keys(&[Keyboard::H, Keyboard::E])),
+                (2, keys(&[Keyboard::H, Keyboard::E, Keyboard::L])),
+                // The second L needs the first one let go
+                (3, Report::release()),
+                (4, keys(&[Keyboard::L])),
+                (5, keys(&[Keyboard::L, Keyboard::O])),
+                (6, keys(&[Keyboard::L, Keyboard::O, Keyboard::Space])),
+                // Shifted chars start their own run
+                (7, Report::release()),
+                (8, keys(&[shift, Keyboard::W])),
+                (9, Report::release()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rollover_runs_fit_in_one_report() {
+        let sent = Pad::paced(b"1:1:NKRO").type_snippet("abcdefg");
+        let six = [Keyboard::A, Keyboard::B, Keyboard::C, Keyboard::D, Keyboard::E, Keyboard::F];
+        assert_eq!(sent[5], (5, keys(&six)));
+        assert_eq!(sent[6], (6, Report::release()));
+        assert_eq!(sent[7], (7, keys(&[Keyboard::G])));
+    }
+
+    #[test]
+    fn speed_escape_changes_pacing_mid_snippet() {
+        assert_eq!(
+            Pad::paced(b"30:20").type_snippet("a{SPEED 2 1 NKRO}bc{ENTER}"),
+            [
+                (0, keys(&[Keyboard::A])),
+                (30, Report::release()),
+                (50, keys(&[Keyboard::B])),
+                (52, keys(&[Keyboard::B, Keyboard::C])),
+                (54, Report::release()),
+                (55, keys(&[Keyboard::ReturnEnter])),
+                (105, Report::release()),
+            ]
+        );
+    }
+
+    // Just the key-down reports, ignoring the releases between them
+    fn presses(sent: Vec<(u32, Report)>) -> Vec<Report> {
+        let mut prev = Report::release();
+        let mut down = Vec::new();
+        for report in reports(sent) {
+            if let (Report::Keys(now), Report::Keys(before)) = (&report, &prev) {
+                if now.len() > before.len() {
+                    down.push(report.clone());
+                }
+            }
+            prev = report;
+        }
+        down
+    }
+
+    #[test]
+    fn unicode_skipped_when_off() {
+        let mut pad = Pad::unicode(UnicodeMode::Off, HostLayout::Qwerty);
+        assert!(pad.type_snippet("é").is_empty());
+    }
+
+    #[test]
+    fn unicode_macos_types_utf16_units() {
+        let alt = |key| keys(&[Keyboard::LeftAlt, key]);
+        let sent = Pad::unicode(UnicodeMode::MacOs, HostLayout::Colemak).type_snippet("é");
+        assert_eq!(sent.last().map(|(_, report)| report), Some(&Report::release()));
+        assert_eq!(
+            presses(sent),
+            [
+                alt(Keyboard::Keyboard0),
+                alt(Keyboard::Keyboard0),
+                alt(Keyboard::E),
+                alt(Keyboard::Keyboard9),
+            ]
+        );
+        // U+1F680 is the surrogate pair D83D DE80
+        let rocket = Pad::unicode(UnicodeMode::MacOs, HostLayout::Qwerty).type_snippet("🚀");
+        assert_eq!(presses(rocket).len(), 8);
+    }
+
+    #[test]
+    fn unicode_linux_follows_layout() {
+        let sent = Pad::unicode(UnicodeMode::Linux, HostLayout::Colemak).type_snippet("é");
+        assert_eq!(
+            presses(sent),
+            [
+                // Colemak U and E sit on the US I and K keys
+                keys(&[Keyboard::LeftControl, Keyboard::LeftShift, Keyboard::I]),
+                keys(&[Keyboard::K]),
+                keys(&[Keyboard::Keyboard9]),
+                keys(&[Keyboard::Space]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_windows_uses_keypad() {
+        let alt = |key| keys(&[Keyboard::LeftAlt, key]);
+        let sent = Pad::unicode(UnicodeMode::Windows, HostLayout::Qwerty).type_snippet("€");
+        assert_eq!(
+            presses(sent),
+            [
+                keys(&[Keyboard::LeftAlt]),
+                alt(Keyboard::KeypadAdd),
+                alt(Keyboard::Keypad2),
+                alt(Keyboard::Keypad0),
+                alt(Keyboard::A),
+                alt(Keyboard::C),
+            ]
+        );
+    }
+
+    #[test]
+    fn held_repeat_stops_on_release() {
+        let mut pad = Pad::new();
+        let mut last = 0;
+        for now in 0..3000 {
+            // Held for a second, repeating every 50 ms
+            if now < 1000 && now % 50 == 0 {
+                pad.run(KeyAction::Repeat(&[Keyboard::UpArrow]));
+            }
+            if pad.scheduler.poll(now).is_some() {
+                last = now;
+            }
+        }
+        assert!(last <= 1000 + CHORD_HOLD_MS, "still repeating at {} ms", last);
+    }
+
+    #[test]
+    fn actions_queue_behind_each_other() {
+        let mut pad = Pad::new();
+        pad.run(KeyAction::Consumer(Consumer::Mute));
+        pad.run(KeyAction::Scroll(-1));
+        assert_eq!(
+            pad.drain(),
+            [
+                (0, Report::Consumer(Some(Consumer::Mute))),
+                (50, Report::Consumer(None)),
+                (60, Report::Scroll(-1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn state_actions_apply_immediately() {
+        let mut pad = Pad::new();
+        pad.run(KeyAction::Layer(LayerAction::ToggleSnippet));
+        pad.run(KeyAction::Notify("hi"));
+        assert_eq!(pad.state.layer, crate::keymap::Layer::Snippet);
+        assert_eq!(pad.out, b"hi\r\n");
+        assert!(pad.scheduler.is_idle());
+    }
+
+    #[test]
+    fn recorded_macro_plays_back() {
+        let mut pad = Pad::new();
+        pad.state.events_enabled = true;
+        pad.run(KeyAction::Record);
+        pad.run(KeyAction::Chord(&[Keyboard::LeftGUI, Keyboard::C]));
+        pad.run(KeyAction::Taps(&[&[Keyboard::Tab]]));
+        // Keys are still sent while recording
+        assert_eq!(pad.drain().len(), 4);
+        pad.run(KeyAction::Snippet(3));
+        assert!(pad.scheduler.is_idle());
+        assert_eq!(pad.out, b"EVT:REC:START\r\nEVT:REC:SAVE:4\r\n");
+
+        pad.run(KeyAction::Snippet(3));
+        assert_eq!(
+            pad.drain(),
+            [
+                (0, keys(&[Keyboard::LeftGUI, Keyboard::C])),
+                (50, Report::release()),
+                (100, keys(&[Keyboard::Tab])),
+                (150, Report::release()),
+            ]
+        );
+    }
+
+    #[test]
+    fn record_again_cancels() {
+        let mut pad = Pad::new();
+        pad.run(KeyAction::Record);
+        pad.run(KeyAction::Chord(&[Keyboard::A]));
+        pad.run(KeyAction::Record);
+        assert!(pad.state.recording.is_none());
+        assert!(pad.state.settings.macros.iter().all(|m| m.is_empty()));
+    }
+}
@@ -0,0 +1,325 @@
+use heapless::String;
+use smart_leds::RGB8;
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::keymap::Layer;
+use crate::layout::{HostLayout, UnicodeMode};
+use crate::macros::{Chord, Macro};
+use crate::scheduler::Pacing;
+use crate::{MESSAGE_LEN, NUM_LEDS, SNIPPET_COUNT, SNIPPET_LEN};
+
+pub const DEFAULT_BRIGHTNESS: u8 = 32;
+
+pub const DEFAULT_SNIPPETS: [&str; SNIPPET_COUNT] = [
+    "!td", "!sh", ":rocket:", "snip04", "snip05",
+    "snip06", "snip07", "snip08", "snip09", "snip10", "snip11",
+];
+
+// Everything the host can change at runtime that should survive a reset
+#[derive(Clone)]
+pub struct Settings {
+    pub brightness: u8,
+    pub layer: Layer,
+    pub layout: HostLayout,
+    pub unicode: UnicodeMode,
+    // How fast snippets are typed
+    pub pacing: Pacing,
+    pub colors: Option<[RGB8; NUM_LEDS]>,
+    pub message: String<MESSAGE_LEN>,
+    pub snippets: [String<SNIPPET_LEN>; SNIPPET_COUNT],
+    // A recorded macro plays instead of its slot's snippet text
+    pub macros: [Macro; SNIPPET_COUNT],
+}
+
+impl Settings {
+    pub fn defaults() -> Self {
+        let mut snippets: [String<SNIPPET_LEN>; SNIPPET_COUNT] = Default::default();
+        for (slot, text) in snippets.iter_mut().zip(DEFAULT_SNIPPETS.iter()) {
+            let _ = slot.push_str(text);
+        }
+        Self {
+            brightness: DEFAULT_BRIGHTNESS,
+            layer: Layer::Vibe,
+            layout: HostLayout::Colemak,
+            unicode: UnicodeMode::Off,
+            pacing: Pacing::DEFAULT,
+            colors: None,
+            message: String::new(),
+            snippets,
+            macros: Default::default(),
+        }
+    }
+
+    // Payload layout (version 4):
+    //   brightness u8, layer u8, layout u8, unicode u8,
+    //   hold ms u8, gap ms u8, rollover u8,
+    //   has_colors u8, 12 x (r, g, b),
+    //   message (len u8 + bytes), 11 x snippet (len u8 + bytes),
+    //   11 x macro (chord count u8, each chord: len u8 + key usages)
+    pub fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut w = ByteWriter { buf, pos: 0 };
+        w.put(&[self.brightness, self.layer as u8, self.layout as u8, self.unicode as u8])?;
+        w.put(&[self.pacing.hold_ms, self.pacing.gap_ms, self.pacing.rollover as u8])?;
+        w.put(&[self.colors.is_some() as u8])?;
+        for c in self.colors.unwrap_or([RGB8::default(); NUM_LEDS]) {
+            w.put(&[c.r, c.g, c.b])?;
+        }
+        w.put_str(&self.message)?;
+        for snippet in &self.snippets {
+            w.put_str(snippet)?;
+        }
+        for steps in &self.macros {
+            w.put(&[steps.len() as u8])?;
+            for chord in steps {
+                w.put(&[chord.len() as u8])?;
+                for &key in chord {
+                    w.put(&[key.into()])?;
+                }
+            }
+        }
+        Some(w.pos)
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let mut r = ByteReader { buf, pos: 0 };
+        let brightness = r.u8()?;
+        let layer = Layer::from_u8(r.u8()?)?;
+        let layout = HostLayout::from_u8(r.u8()?)?;
+        let unicode = UnicodeMode::from_u8(r.u8()?)?;
+        // Zero times would send reports faster than the host polls
+        let (hold_ms, gap_ms, rollover) = (r.u8()?, r.u8()?, r.u8()? != 0);
+        let pacing = Pacing::new(hold_ms, gap_ms, rollover).unwrap_or(Pacing::DEFAULT);
+        let has_colors = r.u8()? != 0;
+        let mut colors = [RGB8::default(); NUM_LEDS];
+        for c in colors.iter_mut() {
+            let rgb = r.take(3)?;
+            *c = RGB8::new(rgb[0], rgb[1], rgb[2]);
+        }
+        let message = r.string()?;
+        let mut snippets: [String<SNIPPET_LEN>; SNIPPET_COUNT] = Default::default();
+        for snippet in snippets.iter_mut() {
+            *snippet = r.string()?;
+        }
+        let mut macros: [Macro; SNIPPET_COUNT] = Default::default();
+        for steps in macros.iter_mut() {
+            for _ in 0..r.u8()? {
+                let len = r.u8()? as usize;
+                let mut chord = Chord::new();
+                for &code in r.take(len)? {
+                    chord.push(Keyboard::from(code)).ok()?;
+                }
+                steps.push(chord).ok()?;
+            }
+        }
+        Some(Self {
+            brightness,
+            layer,
+            layout,
+            unicode,
+            pacing,
+            colors: if has_colors { Some(colors) } else { None },
+            message,
+            snippets,
+            macros,
+        })
+    }
+}
+
+struct ByteWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl ByteWriter<'_> {
+    fn put(&mut self, data: &[u8]) -> Option<()> {
+        let end = self.pos.checked_add(data.len())?;
+        self.buf.get_mut(self.pos..end)?.copy_from_slice(data);
+        self.pos = end;
+        Some(())
+    }
+
+    fn put_str(&mut self, s: &str) -> Option<()> {
+        self.put(&[u8::try_from(s.len()).ok()?])?;
+        self.put(s.as_bytes())
+    }
+}
+
+struct ByteReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let data = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(data)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn string<const N: usize>(&mut self) -> Option<String<N>> {
+        let len = self.u8()? as usize;
+        let text = core::str::from_utf8(self.take(len)?).ok()?;
+        String::try_from(text).ok()
+    }
+}
+
+// CRC-32 (IEEE 802.3), bitwise to keep the table out of flash
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// =============================================================================
+// Flash records
+// =============================================================================
+
+// Records are appended round-robin through the settings region; see the
+// firmware's SettingsStore for how slots are chosen and erased
+pub const RECORD_SIZE: usize = 2048;
+
+// Header: magic u32, version u16, payload len u16, sequence u32
+// followed by the payload and a CRC-32 over header + payload
+const RECORD_MAGIC: u32 = 0x5653_5450; // "PTSV"
+// Records of any other version are ignored, so bumping this resets the
+// pad to defaults once
+const RECORD_VERSION: u16 = 4;
+const HEADER_LEN: usize = 12;
+
+// Builds a complete record for `settings`, leaving unused bytes erased (0xFF)
+pub fn encode_record(settings: &Settings, seq: u32) -> Option<[u8; RECORD_SIZE]> {
+    let mut record = [0xFF_u8; RECORD_SIZE];
+    let len = settings.encode(&mut record[HEADER_LEN..RECORD_SIZE - 4])?;
+    record[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    record[4..6].copy_from_slice(&RECORD_VERSION.to_le_bytes());
+    record[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+    record[8..12].copy_from_slice(&seq.to_le_bytes());
+    let crc = crc32(&record[..HEADER_LEN + len]);
+    record[HEADER_LEN + len..HEADER_LEN + len + 4].copy_from_slice(&crc.to_le_bytes());
+    Some(record)
+}
+
+// Returns (sequence, payload) if `record` is intact and of this version
+pub fn decode_record(record: &[u8]) -> Option<(u32, &[u8])> {
+    let word = |i: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(record.get(i..i + 4)?.try_into().ok()?))
+    };
+    if word(0)? != RECORD_MAGIC {
+        return None;
+    }
+    let version = u16::from_le_bytes([record[4], record[5]]);
+    let len = u16::from_le_bytes([record[6], record[7]]) as usize;
+    if version != RECORD_VERSION || HEADER_LEN + len + 4 > record.len() {
+        return None;
+    }
+    if word(HEADER_LEN + len)? != crc32(&record[..HEADER_LEN + len]) {
+        return None;
+    }
+    Some((word(8)?, &record[HEADER_LEN..HEADER_LEN + len]))
+}
+
+// Sequence numbers wrap, so compare by signed distance
+pub fn is_newer(seq: u32, than: u32) -> bool {
+    (seq.wrapping_sub(than) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macros::{parse_macro, MACRO_STEPS};
+    use crate::scheduler::MAX_REPORT_KEYS;
+
+    fn custom() -> Settings {
+        let mut settings = Settings::defaults();
+        settings.brightness = 200;
+        settings.layer = Layer::Media;
+        settings.unicode = UnicodeMode::Linux;
+        settings.pacing = Pacing::parse(b"4:2:NKRO", b':').unwrap();
+        settings.colors = Some([RGB8::new(1, 2, 3); NUM_LEDS]);
+        let _ = settings.message.push_str("building");
+        settings.snippets[4] = String::try_from("git status").unwrap();
+        settings.macros[2] = parse_macro(b"GUI+C,TAB,GUI+V").unwrap();
+        settings
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let record = encode_record(&custom(), 7).unwrap();
+        let (seq, payload) = decode_record(&record).unwrap();
+        assert_eq!(seq, 7);
+        let settings = Settings::decode(payload).unwrap();
+        assert_eq!(settings.brightness, 200);
+        assert_eq!(settings.layer, Layer::Media);
+        assert_eq!(settings.unicode, UnicodeMode::Linux);
+        assert_eq!(settings.pacing, custom().pacing);
+        assert_eq!(settings.colors, Some([RGB8::new(1, 2, 3); NUM_LEDS]));
+        assert_eq!(settings.message.as_str(), "building");
+        assert_eq!(settings.snippets[4].as_str(), "git status");
+        assert_eq!(settings.snippets[0].as_str(), "!td");
+        assert_eq!(settings.macros[2], custom().macros[2]);
+        assert!(settings.macros[0].is_empty());
+    }
+
+    #[test]
+    fn full_snippets_and_macros_fit_in_a_record() {
+        let mut settings = custom();
+        for snippet in settings.snippets.iter_mut() {
+            while snippet.push('x').is_ok() {}
+        }
+        let chord = Chord::from_slice(&[Keyboard::LeftShift; MAX_REPORT_KEYS]).unwrap();
+        for steps in settings.macros.iter_mut() {
+            steps.clear();
+            while steps.push(chord.clone()).is_ok() {}
+        }
+        assert_eq!(settings.macros[0].len(), MACRO_STEPS);
+        let record = encode_record(&settings, 0).unwrap();
+        let (_, payload) = decode_record(&record).unwrap();
+        assert_eq!(Settings::decode(payload).unwrap().macros, settings.macros);
+    }
+
+    #[test]
+    fn invalid_pacing_loads_as_default() {
+        let mut settings = custom();
+        settings.pacing.hold_ms = 0;
+        let record = encode_record(&settings, 3).unwrap();
+        let (_, payload) = decode_record(&record).unwrap();
+        let settings = Settings::decode(payload).unwrap();
+        assert_eq!(settings.pacing, Pacing::DEFAULT);
+        assert_eq!(settings.brightness, 200);
+    }
+
+    #[test]
+    fn corrupted_record_is_rejected() {
+        let mut record = encode_record(&custom(), 1).unwrap();
+        record[20] ^= 0x01;
+        assert!(decode_record(&record).is_none());
+    }
+
+    #[test]
+    fn erased_flash_is_not_a_record() {
+        assert!(decode_record(&[0xFF; RECORD_SIZE]).is_none());
+    }
+
+    #[test]
+    fn sequence_comparison_wraps() {
+        assert!(is_newer(1, 0));
+        assert!(!is_newer(0, 1));
+        assert!(is_newer(0, u32::MAX));
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,260 @@
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::gesture::Gesture;
+use crate::keymap::{button_action, KeyAction, Layer};
+use crate::macros::{Chord, Macro};
+use crate::menu::{Menu, MenuAction};
+use crate::scheduler::MAX_REPORT_KEYS;
+use crate::settings::Settings;
+use crate::{SNIPPET_COUNT, SNIPPET_LABEL_LEN};
+
+// Shown instead of the snippet text on slots holding a macro
+const MACRO_LABELS: [&str; SNIPPET_COUNT] = [
+    "MAC1", "MAC2", "MAC3", "MAC4", "MAC5", "MAC6", "MAC7", "MAC8", "MAC9", "MAC10", "MAC11",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Status {
+    Idle,
+    Run,
+    Wait,
+    Err,
+}
+
+impl Status {
+    pub fn icon(self) -> char {
+        match self {
+            Status::Idle => 'o',
+            Status::Run => '*',
+            Status::Wait => '~',
+            Status::Err => 'X',
+        }
+    }
+}
+
+pub struct State {
+    pub layer: Layer,
+    pub prev_layer: Layer,
+    // Last layer sent to the host as EVT:LAYER
+    pub reported_layer: Layer,
+    pub status: Status,
+    pub settings: Settings,
+    pub events_enabled: bool,
+    pub display_dirty: bool,
+    pub settings_dirty: bool,
+    // Chords captured so far while a macro is being recorded
+    pub recording: Option<Macro>,
+    // Set by a host command for the firmware to run like a key press
+    pub host_action: Option<KeyAction>,
+    // The settings menu, while it's open
+    pub menu: Option<Menu>,
+}
+
+impl State {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            layer: settings.layer,
+            prev_layer: settings.layer,
+            reported_layer: settings.layer,
+            status: Status::Idle,
+            settings,
+            events_enabled: false,
+            display_dirty: true,
+            settings_dirty: false,
+            recording: None,
+            host_action: None,
+            menu: None,
+        }
+    }
+
+    // Turning the knob works the settings menu while it's open
+    pub fn rotation(&self, step: i8) -> KeyAction {
+        match self.menu {
+            Some(_) => KeyAction::Menu(MenuAction::Turn(step)),
+            None => self.layer.rotation(step),
+        }
+    }
+
+    pub fn button_action(&self, gesture: Gesture) -> Option<KeyAction> {
+        match gesture {
+            Gesture::Tap if self.menu.is_some() => Some(KeyAction::Menu(MenuAction::Select)),
+            _ => button_action(gesture),
+        }
+    }
+
+    pub fn toggle_snippet(&mut self) {
+        if self.layer == Layer::Snippet {
+            self.layer = self.prev_layer;
+        } else {
+            self.prev_layer = self.layer;
+            self.layer = Layer::Snippet;
+        }
+        self.display_dirty = true;
+    }
+
+    pub fn set_layer(&mut self, layer: Layer) {
+        if layer != Layer::Snippet {
+            self.layer = layer;
+            self.prev_layer = layer;
+            self.settings.layer = layer;
+            self.display_dirty = true;
+            self.settings_dirty = true;
+        }
+    }
+
+    pub fn step_brightness(&mut self, step: i8) {
+        self.settings.brightness = self.settings.brightness.saturating_add_signed(step);
+        self.settings_dirty = true;
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Macro::new());
+        self.display_dirty = true;
+    }
+
+    pub fn cancel_recording(&mut self) {
+        self.recording = None;
+        self.display_dirty = true;
+    }
+
+    // Captures one chord if recording; chords past the macro length are dropped
+    pub fn record(&mut self, keys: &[Keyboard]) {
+        if let Some(steps) = self.recording.as_mut() {
+            let chord: Chord = keys.iter().copied().take(MAX_REPORT_KEYS).collect();
+            let _ = steps.push(chord);
+        }
+    }
+
+    // Stores the recording in a snippet slot; an empty recording clears it
+    pub fn save_recording(&mut self, slot: usize) {
+        if let Some(steps) = self.recording.take() {
+            self.settings.macros[slot] = steps;
+            self.display_dirty = true;
+            self.settings_dirty = true;
+        }
+    }
+
+    // Snippet keys are labelled with the start of their text
+    pub fn label(&self, key: usize) -> &str {
+        if let KeyAction::Snippet(slot) = self.layer.action(key) {
+            if !self.settings.macros[slot].is_empty() {
+                return MACRO_LABELS[slot];
+            }
+            let text = self.settings.snippets[slot].as_str();
+            if !text.is_empty() {
+                return match text.char_indices().nth(SNIPPET_LABEL_LEN) {
+                    Some((end, _)) => &text[..end],
+                    None => text,
+                };
+            }
+        }
+        self.layer.label(key)
+    }
+
+    pub fn reset(&mut self) {
+        self.settings.colors = None;
+        self.settings.message.clear();
+        self.status = Status::Idle;
+        self.display_dirty = true;
+        self.settings_dirty = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        State::new(Settings::defaults())
+    }
+
+    #[test]
+    fn snippet_toggle_returns_to_previous_layer() {
+        let mut state = state();
+        state.set_layer(Layer::Media);
+        state.toggle_snippet();
+        assert_eq!(state.layer, Layer::Snippet);
+        state.toggle_snippet();
+        assert_eq!(state.layer, Layer::Media);
+    }
+
+    #[test]
+    fn set_layer_refuses_snippet() {
+        let mut state = state();
+        state.set_layer(Layer::Snippet);
+        assert_eq!(state.layer, Layer::Vibe);
+        assert!(!state.settings_dirty);
+    }
+
+    #[test]
+    fn set_layer_is_persisted() {
+        let mut state = state();
+        state.set_layer(Layer::Media);
+        assert_eq!(state.settings.layer, Layer::Media);
+        assert!(state.settings_dirty);
+    }
+
+    #[test]
+    fn brightness_steps_saturate() {
+        let mut state = state();
+        state.settings.brightness = 250;
+        state.step_brightness(16);
+        assert_eq!(state.settings.brightness, 255);
+        state.step_brightness(-16);
+        assert_eq!(state.settings.brightness, 239);
+        assert!(state.settings_dirty);
+    }
+
+    #[test]
+    fn starts_on_saved_layer() {
+        let mut settings = Settings::defaults();
+        settings.layer = Layer::Media;
+        let mut state = State::new(settings);
+        assert_eq!(state.layer, Layer::Media);
+        state.toggle_snippet();
+        state.toggle_snippet();
+        assert_eq!(state.layer, Layer::Media);
+    }
+
+    #[test]
+    fn snippet_labels_follow_snippet_text() {
+        let mut state = state();
+        state.toggle_snippet();
+        assert_eq!(state.label(0), "!td");
+        assert_eq!(state.label(2), ":rocke");
+        state.settings.snippets[3].clear();
+        assert_eq!(state.label(3), "SNP04");
+        assert_eq!(state.label(11), "EXIT");
+    }
+
+    #[test]
+    fn recording_saves_to_a_slot() {
+        let mut state = state();
+        state.record(&[Keyboard::A]);
+        assert!(state.recording.is_none());
+
+        state.start_recording();
+        state.record(&[Keyboard::LeftGUI, Keyboard::C]);
+        state.record(&[Keyboard::Tab]);
+        state.save_recording(4);
+        assert!(state.recording.is_none());
+        assert_eq!(state.settings.macros[4].len(), 2);
+        assert!(state.settings_dirty);
+
+        state.toggle_snippet();
+        assert_eq!(state.label(4), "MAC5");
+        assert_eq!(state.label(0), "!td");
+    }
+
+    #[test]
+    fn reset_clears_host_overrides() {
+        let mut state = state();
+        state.settings.colors = Some(Default::default());
+        let _ = state.settings.message.push_str("hi");
+        state.status = Status::Run;
+        state.reset();
+        assert!(state.settings.colors.is_none());
+        assert!(state.settings.message.is_empty());
+        assert_eq!(state.status, Status::Idle);
+    }
+}
@@ -1,14 +1,7 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 16K
+    /* Settings store, see SETTINGS_OFFSET in src/main.rs */
+    SETTINGS : ORIGIN = 0x10000000 + 2048K - 16K, LENGTH = 16K
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
-
-EXTERN(BOOT2_FIRMWARE)
-
-SECTIONS {
-    .boot2 ORIGIN(BOOT2) :
-    {
-        KEEP(*(.boot2));
-    } > BOOT2
-} INSERT BEFORE .text;
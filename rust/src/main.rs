@@ -2,990 +2,726 @@
 #![no_main]
 
 use core::cell::RefCell;
-use critical_section::Mutex;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_executor::Spawner;
+use embassy_rp::flash::{Blocking, Flash};
+use embassy_rp::gpio::{Input, Level, Output, Pull};
+use embassy_rp::peripherals::{DMA_CH0, FLASH, PIO0, SPI1, USB};
+use embassy_rp::pio::Pio;
+use embassy_rp::pio_programs::ws2812::{Grb, PioWs2812, PioWs2812Program};
+use embassy_rp::pwm::{self, Pwm};
+use embassy_rp::spi::{self, Spi};
+use embassy_rp::usb::{self, Driver};
+use embassy_rp::watchdog::Watchdog;
+use embassy_rp::{bind_interrupts, clocks, dma, pio};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::pipe::Pipe;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass};
+use embassy_usb::class::hid::{self, HidBootProtocol, HidSubclass, HidWriter};
+use embassy_usb::{Builder, Handler, UsbDevice};
 use embedded_graphics::{
     mono_font::{ascii::FONT_6X10, MonoTextStyle},
     pixelcolor::BinaryColor,
     prelude::*,
     text::Text,
 };
-use embedded_hal::digital::v2::{InputPin, OutputPin};
-use embedded_hal::PwmPin;
-use frunk::{HCons, HNil};
-use heapless::String;
+use macropad_core::debounce::{DebounceMode, Debouncer};
+use macropad_core::encoder::Quadrature;
+use macropad_core::gesture::GestureDetector;
+use macropad_core::hold::{HoldTiming, HoldTracker};
+use macropad_core::keymap::KeyAction;
+use macropad_core::leds::compute_leds;
+use macropad_core::menu::{self, MENU_ITEMS};
+use macropad_core::protocol::{
+    handle_line, send_event, send_key_event, send_layer_event, SerialOut, MAX_LINE_LEN,
+};
+use macropad_core::scheduler::{Report, Scheduler};
+use macropad_core::settings::{decode_record, encode_record, is_newer, Settings, RECORD_SIZE};
+use macropad_core::state::State;
+use macropad_core::{NUM_KEYS, NUM_LEDS, SERIAL_BUF_LEN, SNIPPET_COUNT};
+use packed_struct::PackedStruct;
 use panic_halt as _;
-use rp2040_hal::{
-    clocks::{init_clocks_and_plls, Clock},
-    entry,
-    gpio::{FunctionSpi, Pins},
-    pac,
-    pac::interrupt,
-    pio::PIOExt,
-    pwm::Slices,
-    spi::Spi,
-    timer::Timer,
-    usb::UsbBus,
-    watchdog::Watchdog,
-    Sio,
+use sh1106::{interface::SpiInterface, prelude::*, Builder as DisplayBuilder};
+use smart_leds::{brightness, RGB8};
+use static_cell::StaticCell;
+use usbd_human_interface_device::device::consumer::{
+    MultipleConsumerReport, MULTIPLE_CODE_REPORT_DESCRIPTOR,
+};
+use usbd_human_interface_device::device::keyboard::{
+    NKROBootKeyboardReport, NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR,
 };
-use sh1106::{prelude::*, Builder};
-use smart_leds::{brightness, SmartLedsWrite, RGB8};
-use usb_device::{class_prelude::*, prelude::*};
-use usbd_human_interface_device::device::keyboard::{NKROBootKeyboard, NKROBootKeyboardConfig};
-use usbd_human_interface_device::page::Keyboard;
-use usbd_human_interface_device::prelude::*;
-use usbd_serial::SerialPort;
-use ws2812_pio::Ws2812;
-
-#[link_section = ".boot2"]
-#[used]
-pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
-
-const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
-const NUM_LEDS: usize = 12;
-const BRIGHTNESS_LEVEL: u8 = 32;
+use usbd_human_interface_device::device::mouse::{WheelMouseReport, WHEEL_MOUSE_REPORT_DESCRIPTOR};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => usb::InterruptHandler<USB>;
+    PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
+    DMA_IRQ_0 => dma::InterruptHandler<DMA_CH0>;
+});
+
+// Reported by VER?
+const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Key switches report the first edge and are then ignored for this long
+const DEBOUNCE_MODE: DebounceMode = DebounceMode::Eager;
+const DEBOUNCE_MS: u32 = 20;
+
+// Tap/hold threshold and typematic repeat for held keys
+const HOLD_TIMING: HoldTiming = HoldTiming {
+    hold_ms: 200,
+    repeat_delay_ms: 400,
+    repeat_interval_ms: 50,
+};
+
+// Keys, encoder and button are sampled this often
+const INPUT_POLL: Duration = Duration::from_millis(1);
+// LED effects advance one step per frame
+const LED_FRAME: Duration = Duration::from_millis(10);
+const DISPLAY_POLL: Duration = Duration::from_millis(20);
+
+// Resets the pad if not fed for this long. It's only fed while USB is
+// configured and awake, so a host that suspends us for too long gets a reset.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_millis(5000);
 
 // =============================================================================
-// Layers & Status
+// Shared State
 // =============================================================================
 
-#[derive(Clone, Copy, PartialEq)]
-enum Layer {
-    Vibe,
-    Media,
-    Snippet,
+// Every task runs on the one thread-mode executor and never awaits while
+// holding the state, so borrows can't overlap
+type SharedState = Mutex<ThreadModeRawMutex, RefCell<State>>;
+
+fn with_state<R>(state: &SharedState, f: impl FnOnce(&mut State) -> R) -> R {
+    state.lock(|cell| f(&mut cell.borrow_mut()))
 }
 
-impl Layer {
-    fn next(self) -> Self {
-        match self {
-            Layer::Vibe => Layer::Media,
-            Layer::Media => Layer::Vibe,
-            Layer::Snippet => Layer::Snippet, // encoder doesn't change snippet
-        }
-    }
+// Key actions from the inputs to the scheduler
+static ACTIONS: Channel<ThreadModeRawMutex, KeyAction, 16> = Channel::new();
+// HID reports from the scheduler to the USB endpoints
+static REPORTS: Channel<ThreadModeRawMutex, Report, 4> = Channel::new();
+// Replies and events waiting for the CDC port. Commands write their replies
+// without waiting, so this holds the longest, a MAC? dump of full macros.
+static SERIAL_TX: Pipe<ThreadModeRawMutex, { (SNIPPET_COUNT + 1) * MAX_LINE_LEN }> = Pipe::new();
 
-    fn name(self) -> &'static str {
-        match self {
-            Layer::Vibe => "VIBE",
-            Layer::Media => "MEDIA",
-            Layer::Snippet => "SNIPPET",
-        }
-    }
+static USB_CONFIGURED: AtomicBool = AtomicBool::new(false);
+static USB_SUSPENDED: AtomicBool = AtomicBool::new(false);
 
-    fn default_labels(self) -> [&'static str; 12] {
-        match self {
-            Layer::Vibe => ["REC", "STOP", "CYCLE", "ESC", "ENTER", "TAB", "UP", "DOWN", "SAVE", "COPY", "PASTE", "SNIP"],
-            Layer::Media => ["PREV", "PLAY", "NEXT", "MUTE", "VOL-", "VOL+", "RWD", "STOP", "FWD", "MIC", "CAM", "SNIP"],
-            Layer::Snippet => ["!td", "!sh", "RKT", "SNP04", "SNP05", "SNP06", "SNP07", "SNP08", "SNP09", "SNP10", "SNP11", "EXIT"],
-        }
-    }
+fn now_ms() -> u32 {
+    Instant::now().as_millis() as u32
 }
 
-#[derive(Clone, Copy, PartialEq)]
-enum Status {
-    Idle,
-    Run,
-    Wait,
-    Err,
-}
+// Lines that don't fit are dropped whole rather than split, so a host that
+// stops reading never sees half a line when it comes back
+struct PipeOut;
 
-impl Status {
-    fn icon(self) -> char {
-        match self {
-            Status::Idle => 'o',
-            Status::Run => '*',
-            Status::Wait => '~',
-            Status::Err => 'X',
+impl SerialOut for PipeOut {
+    fn write(&mut self, data: &[u8]) {
+        if SERIAL_TX.free_capacity() >= data.len() {
+            let _ = SERIAL_TX.try_write(data);
         }
     }
 }
 
 // =============================================================================
-// State
+// Flash Storage
 // =============================================================================
 
-struct State {
-    layer: Layer,
-    prev_layer: Layer,
-    message: String<20>,
-    status: Status,
-    custom_colors: Option<[RGB8; 12]>,
-    display_dirty: bool,
-}
+// Must match the SETTINGS region reserved in memory.x
+const FLASH_SIZE: usize = 2048 * 1024;
+const SETTINGS_SIZE: u32 = 16 * 1024;
+const SETTINGS_OFFSET: u32 = FLASH_SIZE as u32 - SETTINGS_SIZE;
+const SECTOR_SIZE: u32 = 4096;
 
-impl State {
-    fn new() -> Self {
-        Self {
-            layer: Layer::Vibe,
-            prev_layer: Layer::Vibe,
-            message: String::new(),
-            status: Status::Idle,
-            custom_colors: None,
-            display_dirty: true,
-        }
-    }
+// Records are appended round-robin through the region; a sector is only
+// erased when the ring wraps back into it, so each save costs one page
+// program rather than a sector erase
+const SLOT_COUNT: usize = SETTINGS_SIZE as usize / RECORD_SIZE;
 
-    fn toggle_snippet(&mut self) {
-        if self.layer == Layer::Snippet {
-            self.layer = self.prev_layer;
-        } else {
-            self.prev_layer = self.layer;
-            self.layer = Layer::Snippet;
-        }
-        self.display_dirty = true;
-    }
+// Wait this long after the last change before writing, so a burst of RGB:
+// commands lands as a single record
+const SETTINGS_SAVE_DELAY: Duration = Duration::from_millis(2000);
 
-    fn set_layer(&mut self, layer: Layer) {
-        if layer != Layer::Snippet {
-            self.layer = layer;
-            self.prev_layer = layer;
-            self.display_dirty = true;
-        }
-    }
+type SettingsFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
 
-    fn reset(&mut self) {
-        self.custom_colors = None;
-        self.message.clear();
-        self.status = Status::Idle;
-        self.display_dirty = true;
-    }
+struct SettingsStore {
+    flash: SettingsFlash,
+    current: Option<(usize, u32)>, // (slot, sequence) of the newest valid record
 }
 
-// =============================================================================
-// USB Globals
-// =============================================================================
+impl SettingsStore {
+    fn slot_offset(slot: usize) -> u32 {
+        SETTINGS_OFFSET + (slot * RECORD_SIZE) as u32
+    }
 
-type MyUsbHidClass = UsbHidClass<'static, UsbBus, HCons<NKROBootKeyboard<'static, UsbBus>, HNil>>;
-
-static USB_DEVICE: Mutex<RefCell<Option<UsbDevice<'static, UsbBus>>>> =
-    Mutex::new(RefCell::new(None));
-static USB_HID: Mutex<RefCell<Option<MyUsbHidClass>>> = Mutex::new(RefCell::new(None));
-static USB_SERIAL: Mutex<RefCell<Option<SerialPort<'static, UsbBus>>>> =
-    Mutex::new(RefCell::new(None));
-
-fn poll_usb() -> Option<UsbDeviceState> {
-    critical_section::with(|cs| {
-        if let Some(usb_dev) = USB_DEVICE.borrow_ref_mut(cs).as_mut() {
-            let mut hid = USB_HID.borrow_ref_mut(cs);
-            let mut serial = USB_SERIAL.borrow_ref_mut(cs);
-            if let (Some(hid), Some(serial)) = (hid.as_mut(), serial.as_mut()) {
-                usb_dev.poll(&mut [hid, serial]);
-            }
-            Some(usb_dev.state())
-        } else {
-            None
+    // Unreadable slots come back blank
+    fn read_slot(&mut self, slot: usize, buf: &mut [u8; RECORD_SIZE]) {
+        if self.flash.blocking_read(Self::slot_offset(slot), buf).is_err() {
+            buf.fill(0xFF);
         }
-    })
-}
+    }
 
-fn tick_usb() {
-    critical_section::with(|cs| {
-        if let Some(hid) = USB_HID.borrow_ref_mut(cs).as_mut() {
-            match hid.tick() {
-                Ok(_) => {}
-                Err(UsbHidError::WouldBlock) => {}
-                Err(_) => {}
+    // Scan for the newest valid record, falling back to compiled defaults
+    fn load(flash: SettingsFlash) -> (Self, Settings) {
+        let mut store = Self { flash, current: None };
+        let mut buf = [0u8; RECORD_SIZE];
+        let mut newest: Option<(usize, u32)> = None;
+        for slot in 0..SLOT_COUNT {
+            store.read_slot(slot, &mut buf);
+            if let Some((seq, _)) = decode_record(&buf) {
+                if newest.is_none_or(|(_, best)| is_newer(seq, best)) {
+                    newest = Some((slot, seq));
+                }
             }
         }
-    });
-}
-
-fn send_keys(keys: &[Keyboard]) {
-    critical_section::with(|cs| {
-        if let Some(hid) = USB_HID.borrow_ref_mut(cs).as_mut() {
-            match hid.device().write_report(keys.iter().copied()) {
-                Ok(_) => {}
-                Err(UsbHidError::WouldBlock) => {}
-                Err(UsbHidError::Duplicate) => {}
-                Err(_) => {}
+        let Some((slot, seq)) = newest else {
+            return (store, Settings::defaults());
+        };
+        store.read_slot(slot, &mut buf);
+        match decode_record(&buf).and_then(|(_, payload)| Settings::decode(payload)) {
+            Some(settings) => {
+                store.current = Some((slot, seq));
+                (store, settings)
             }
+            None => (store, Settings::defaults()),
         }
-    });
-    poll_usb();
-}
-
-fn release_keys() {
-    send_keys(&[]);
-}
+    }
 
-fn read_serial(buf: &mut [u8]) -> usize {
-    critical_section::with(|cs| {
-        if let Some(serial) = USB_SERIAL.borrow_ref_mut(cs).as_mut() {
-            match serial.read(buf) {
-                Ok(count) => count,
-                Err(_) => 0,
+    fn save(&mut self, settings: &Settings) {
+        let (mut slot, seq) = match self.current {
+            Some((slot, seq)) => ((slot + 1) % SLOT_COUNT, seq.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let Some(record) = encode_record(settings, seq) else {
+            return;
+        };
+        let mut buf = [0u8; RECORD_SIZE];
+
+        // Skip the write entirely if nothing changed since the last save
+        if let Some((current, _)) = self.current {
+            self.read_slot(current, &mut buf);
+            let stored = decode_record(&buf).map(|(_, payload)| payload);
+            if stored.is_some() && stored == decode_record(&record).map(|(_, payload)| payload) {
+                return;
             }
-        } else {
-            0
         }
-    })
-}
 
-// =============================================================================
-// Serial Protocol Parser
-// =============================================================================
-
-fn parse_hex_color(s: &[u8]) -> Option<RGB8> {
-    if s.len() != 6 {
-        return None;
-    }
-    let r = hex_byte(&s[0..2])?;
-    let g = hex_byte(&s[2..4])?;
-    let b = hex_byte(&s[4..6])?;
-    Some(RGB8::new(r, g, b))
-}
-
-fn hex_byte(s: &[u8]) -> Option<u8> {
-    if s.len() != 2 {
-        return None;
-    }
-    let high = hex_digit(s[0])?;
-    let low = hex_digit(s[1])?;
-    Some((high << 4) | low)
-}
+        // A slot that isn't blank mid-sector (e.g. a torn write) can't be
+        // programmed over, so move on to the next sector and erase it
+        let slots_per_sector = SECTOR_SIZE as usize / RECORD_SIZE;
+        self.read_slot(slot, &mut buf);
+        if slot % slots_per_sector != 0 && buf.iter().any(|&b| b != 0xFF) {
+            slot = (slot / slots_per_sector + 1) * slots_per_sector % SLOT_COUNT;
+        }
 
-fn hex_digit(c: u8) -> Option<u8> {
-    match c {
-        b'0'..=b'9' => Some(c - b'0'),
-        b'A'..=b'F' => Some(c - b'A' + 10),
-        b'a'..=b'f' => Some(c - b'a' + 10),
-        _ => None,
-    }
-}
+        // The driver runs these from RAM with interrupts off; core 1 is
+        // never started
+        let offset = Self::slot_offset(slot);
+        if offset % SECTOR_SIZE == 0
+            && self.flash.blocking_erase(offset, offset + SECTOR_SIZE).is_err()
+        {
+            return;
+        }
+        if self.flash.blocking_write(offset, &record).is_err() {
+            return;
+        }
 
-fn parse_key_num(s: &[u8]) -> Option<usize> {
-    if s.is_empty() || s.len() > 2 {
-        return None;
-    }
-    let mut n: usize = 0;
-    for &c in s {
-        if c < b'0' || c > b'9' {
-            return None;
+        self.read_slot(slot, &mut buf);
+        if decode_record(&buf).is_some() {
+            self.current = Some((slot, seq));
         }
-        n = n * 10 + (c - b'0') as usize;
-    }
-    if n >= 1 && n <= 12 {
-        Some(n - 1)
-    } else {
-        None
     }
 }
 
-fn process_command(cmd: &[u8], state: &mut State) {
-    if cmd.len() < 4 {
-        return;
-    }
-
-    // MSG:<text>
-    if cmd.starts_with(b"MSG:") {
-        state.message.clear();
-        let text = &cmd[4..];
-        for &c in text.iter().take(20) {
-            if c >= 0x20 && c < 0x7F {
-                let _ = state.message.push(c as char);
+// Persists host changes once they've settled
+#[embassy_executor::task]
+async fn storage_task(mut store: SettingsStore, state: &'static SharedState) {
+    let mut save_at: Option<Instant> = None;
+    loop {
+        Timer::after_millis(100).await;
+        let settings = with_state(state, |s| {
+            if s.settings_dirty {
+                s.settings_dirty = false;
+                save_at = Some(Instant::now() + SETTINGS_SAVE_DELAY);
             }
-        }
-        state.display_dirty = true;
-        return;
-    }
-
-    // STS:<state>
-    if cmd.starts_with(b"STS:") {
-        let status_str = &cmd[4..];
-        state.status = match status_str {
-            b"IDLE" => Status::Idle,
-            b"RUN" => Status::Run,
-            b"WAIT" => Status::Wait,
-            b"ERR" => Status::Err,
-            _ => state.status,
-        };
-        state.display_dirty = true;
-        return;
-    }
-
-    // RGB:<key>:<hex>
-    if cmd.starts_with(b"RGB:") {
-        let rest = &cmd[4..];
-        if let Some(colon_pos) = rest.iter().position(|&c| c == b':') {
-            if let Some(key_idx) = parse_key_num(&rest[..colon_pos]) {
-                if let Some(color) = parse_hex_color(&rest[colon_pos + 1..]) {
-                    if state.custom_colors.is_none() {
-                        state.custom_colors = Some([RGB8::default(); 12]);
-                    }
-                    if let Some(ref mut colors) = state.custom_colors {
-                        colors[key_idx] = color;
-                    }
-                }
+            match save_at {
+                Some(at) if Instant::now() >= at => Some(s.settings.clone()),
+                _ => None,
             }
+        });
+        if let Some(settings) = settings {
+            store.save(&settings);
+            save_at = None;
         }
-        return;
-    }
-
-    // CLR:
-    if cmd.starts_with(b"CLR:") {
-        state.message.clear();
-        state.display_dirty = true;
-        return;
-    }
-
-    // RST:
-    if cmd.starts_with(b"RST:") {
-        state.reset();
-        return;
     }
 }
 
 // =============================================================================
-// LED Effects
+// USB
 // =============================================================================
 
-fn lerp_color(a: RGB8, b: RGB8, t: u8) -> RGB8 {
-    let t16 = t as u16;
-    let inv_t = 255 - t16;
-    RGB8::new(
-        ((a.r as u16 * inv_t + b.r as u16 * t16) / 255) as u8,
-        ((a.g as u16 * inv_t + b.g as u16 * t16) / 255) as u8,
-        ((a.b as u16 * inv_t + b.b as u16 * t16) / 255) as u8,
-    )
-}
+type UsbDriver = Driver<'static, USB>;
 
-fn vibe_gradient(pos: u8) -> RGB8 {
-    // purple -> cyan -> blue
-    let purple = RGB8::new(128, 0, 255);
-    let cyan = RGB8::new(0, 255, 255);
-    let blue = RGB8::new(0, 0, 255);
+// Tracks bus state for the watchdog
+struct UsbStateHandler;
 
-    if pos < 128 {
-        lerp_color(purple, cyan, pos * 2)
-    } else {
-        lerp_color(cyan, blue, (pos - 128) * 2)
+impl Handler for UsbStateHandler {
+    fn reset(&mut self) {
+        USB_CONFIGURED.store(false, Ordering::Relaxed);
     }
-}
 
-fn media_gradient(pos: u8) -> RGB8 {
-    // orange -> pink -> red
-    let orange = RGB8::new(255, 128, 0);
-    let pink = RGB8::new(255, 0, 128);
-    let red = RGB8::new(255, 0, 0);
+    fn configured(&mut self, configured: bool) {
+        USB_CONFIGURED.store(configured, Ordering::Relaxed);
+    }
 
-    if pos < 128 {
-        lerp_color(orange, pink, pos * 2)
-    } else {
-        lerp_color(pink, red, (pos - 128) * 2)
+    fn suspended(&mut self, suspended: bool) {
+        USB_SUSPENDED.store(suspended, Ordering::Relaxed);
     }
 }
 
-fn pulse_green(tick: u32) -> RGB8 {
-    // Sinusoidal pulse using lookup approximation
-    let phase = ((tick / 2) % 256) as u8;
-    let intensity = if phase < 128 {
-        phase * 2
-    } else {
-        (255 - phase) * 2
-    };
-    RGB8::new(0, intensity, 0)
+#[embassy_executor::task]
+async fn usb_task(mut device: UsbDevice<'static, UsbDriver>) -> ! {
+    device.run().await
 }
 
-fn compute_leds(state: &State, tick: u32) -> [RGB8; NUM_LEDS] {
-    let mut leds = [RGB8::default(); NUM_LEDS];
-    let offset = (tick / 2) as u8;
-
-    match state.layer {
-        Layer::Vibe => {
-            for i in 0..11 {
-                let pos = offset.wrapping_add((i as u8) * 23);
-                leds[i] = vibe_gradient(pos);
+// Sends each report from the scheduler to its interface. Write errors mean
+// the host isn't listening, and the report is dropped.
+#[embassy_executor::task]
+async fn hid_task(
+    mut keyboard: HidWriter<'static, UsbDriver, 32>,
+    mut consumer: HidWriter<'static, UsbDriver, 8>,
+    mut mouse: HidWriter<'static, UsbDriver, 8>,
+) {
+    loop {
+        match REPORTS.receive().await {
+            Report::Keys(keys) => {
+                if let Ok(data) = NKROBootKeyboardReport::new(keys).pack() {
+                    let _ = keyboard.write(&data).await;
+                }
             }
-            leds[11] = pulse_green(tick);
-        }
-        Layer::Media => {
-            for i in 0..11 {
-                let pos = offset.wrapping_add((i as u8) * 23);
-                leds[i] = media_gradient(pos);
+            // Consumer reports carry up to 4 simultaneous usages
+            Report::Consumer(code) => {
+                let mut report = MultipleConsumerReport::default();
+                if let Some(code) = code {
+                    report.codes[0] = code;
+                }
+                if let Ok(data) = report.pack() {
+                    let _ = consumer.write(&data).await;
+                }
+            }
+            Report::Scroll(notches) => {
+                let report = WheelMouseReport {
+                    vertical_wheel: notches,
+                    ..Default::default()
+                };
+                if let Ok(data) = report.pack() {
+                    let _ = mouse.write(&data).await;
+                }
             }
-            leds[11] = pulse_green(tick);
         }
-        Layer::Snippet => {
-            for i in 0..11 {
-                leds[i] = RGB8::new(255, 255, 255); // solid white
+    }
+}
+
+// Assembles command lines from the CDC port and answers them
+#[embassy_executor::task]
+async fn serial_rx_task(mut rx: cdc_acm::Receiver<'static, UsbDriver>, state: &'static SharedState) {
+    let mut line = [0u8; SERIAL_BUF_LEN];
+    let mut packet = [0u8; 64];
+    loop {
+        rx.wait_connection().await;
+        // Each connection starts a fresh line; a partial one from before a
+        // disconnect is dropped
+        let mut len = 0;
+        let mut overflow = false;
+        while let Ok(count) = rx.read_packet(&mut packet).await {
+            for &c in &packet[..count] {
+                if c == b'\n' || c == b'\r' {
+                    if len > 0 || overflow {
+                        let action = with_state(state, |s| {
+                            handle_line(&line[..len], overflow, FIRMWARE_VERSION, s, &mut PipeOut);
+                            s.host_action.take()
+                        });
+                        if let Some(action) = action {
+                            queue_action(action);
+                        }
+                        len = 0;
+                        overflow = false;
+                    }
+                } else if len < line.len() {
+                    line[len] = c;
+                    len += 1;
+                } else {
+                    overflow = true;
+                }
             }
-            leds[11] = RGB8::new(0, 255, 0); // solid green
         }
     }
+}
 
-    // Apply custom colors if set
-    if let Some(ref custom) = state.custom_colors {
-        for i in 0..12 {
-            if custom[i].r != 0 || custom[i].g != 0 || custom[i].b != 0 {
-                leds[i] = custom[i];
+#[embassy_executor::task]
+async fn serial_tx_task(mut tx: cdc_acm::Sender<'static, UsbDriver>) {
+    let mut packet = [0u8; 64];
+    loop {
+        tx.wait_connection().await;
+        loop {
+            let count = SERIAL_TX.read(&mut packet).await;
+            if tx.write_packet(&packet[..count]).await.is_err() {
+                break;
+            }
+            // A full packet doesn't end the transfer, so terminate it
+            if count == packet.len() && tx.write_packet(&[]).await.is_err() {
+                break;
             }
         }
     }
+}
 
-    leds
+#[embassy_executor::task]
+async fn watchdog_task(mut watchdog: Watchdog) {
+    watchdog.start(WATCHDOG_TIMEOUT);
+    loop {
+        Timer::after_millis(500).await;
+        if USB_CONFIGURED.load(Ordering::Relaxed) && !USB_SUSPENDED.load(Ordering::Relaxed) {
+            watchdog.feed(WATCHDOG_TIMEOUT);
+        }
+    }
 }
 
 // =============================================================================
-// Key Actions
+// Input and Actions
 // =============================================================================
 
-fn send_string(s: &str, delay: &mut cortex_m::delay::Delay) {
-    for c in s.chars() {
-        let key = char_to_key(c);
-        if let Some((k, shift)) = key {
-            if shift {
-                send_keys(&[Keyboard::LeftShift, k]);
-            } else {
-                send_keys(&[k]);
-            }
-            delay.delay_ms(30_u32);
-            release_keys();
-            delay.delay_ms(20_u32);
-        }
-    }
+struct InputPins {
+    keys: [Input<'static>; NUM_KEYS],
+    encoder_a: Input<'static>,
+    encoder_b: Input<'static>,
+    encoder_btn: Input<'static>,
 }
 
-// Map desired char directly to (Keyboard key, shift) for Colemak layout
-fn char_to_key(c: char) -> Option<(Keyboard, bool)> {
-    // Colemak: maps desired output char to QWERTY physical key
-    // o -> Semicolon (QWERTY ; position = Colemak o)
-    // y -> O (QWERTY o position = Colemak y)
-    // ; -> P (QWERTY p position = Colemak ;)
-    // : -> P+Shift
-    match c {
-        'a' => Some((Keyboard::A, false)),
-        'b' => Some((Keyboard::B, false)),
-        'c' => Some((Keyboard::C, false)),
-        'd' => Some((Keyboard::G, false)),
-        'e' => Some((Keyboard::K, false)),
-        'f' => Some((Keyboard::E, false)),
-        'g' => Some((Keyboard::T, false)),
-        'h' => Some((Keyboard::H, false)),
-        'i' => Some((Keyboard::L, false)),
-        'j' => Some((Keyboard::Y, false)),
-        'k' => Some((Keyboard::N, false)),
-        'l' => Some((Keyboard::U, false)),
-        'm' => Some((Keyboard::M, false)),
-        'n' => Some((Keyboard::J, false)),
-        'o' => Some((Keyboard::Semicolon, false)),
-        'p' => Some((Keyboard::R, false)),
-        'q' => Some((Keyboard::Q, false)),
-        'r' => Some((Keyboard::S, false)),
-        's' => Some((Keyboard::D, false)),
-        't' => Some((Keyboard::F, false)),
-        'u' => Some((Keyboard::I, false)),
-        'v' => Some((Keyboard::V, false)),
-        'w' => Some((Keyboard::W, false)),
-        'x' => Some((Keyboard::X, false)),
-        'y' => Some((Keyboard::O, false)),
-        'z' => Some((Keyboard::Z, false)),
-        'A' => Some((Keyboard::A, true)),
-        'B' => Some((Keyboard::B, true)),
-        'C' => Some((Keyboard::C, true)),
-        'D' => Some((Keyboard::G, true)),
-        'E' => Some((Keyboard::K, true)),
-        'F' => Some((Keyboard::E, true)),
-        'G' => Some((Keyboard::T, true)),
-        'H' => Some((Keyboard::H, true)),
-        'I' => Some((Keyboard::L, true)),
-        'J' => Some((Keyboard::Y, true)),
-        'K' => Some((Keyboard::N, true)),
-        'L' => Some((Keyboard::U, true)),
-        'M' => Some((Keyboard::M, true)),
-        'N' => Some((Keyboard::J, true)),
-        'O' => Some((Keyboard::Semicolon, true)),
-        'P' => Some((Keyboard::R, true)),
-        'Q' => Some((Keyboard::Q, true)),
-        'R' => Some((Keyboard::S, true)),
-        'S' => Some((Keyboard::D, true)),
-        'T' => Some((Keyboard::F, true)),
-        'U' => Some((Keyboard::I, true)),
-        'V' => Some((Keyboard::V, true)),
-        'W' => Some((Keyboard::W, true)),
-        'X' => Some((Keyboard::X, true)),
-        'Y' => Some((Keyboard::O, true)),
-        'Z' => Some((Keyboard::Z, true)),
-        '0' => Some((Keyboard::Keyboard0, false)),
-        '1' => Some((Keyboard::Keyboard1, false)),
-        '2' => Some((Keyboard::Keyboard2, false)),
-        '3' => Some((Keyboard::Keyboard3, false)),
-        '4' => Some((Keyboard::Keyboard4, false)),
-        '5' => Some((Keyboard::Keyboard5, false)),
-        '6' => Some((Keyboard::Keyboard6, false)),
-        '7' => Some((Keyboard::Keyboard7, false)),
-        '8' => Some((Keyboard::Keyboard8, false)),
-        '9' => Some((Keyboard::Keyboard9, false)),
-        ' ' => Some((Keyboard::Space, false)),
-        '\n' => Some((Keyboard::ReturnEnter, false)),
-        '\t' => Some((Keyboard::Tab, false)),
-        '!' => Some((Keyboard::Keyboard1, true)),
-        ':' => Some((Keyboard::P, true)),
-        ';' => Some((Keyboard::P, false)),
-        _ => None,
-    }
+// Dropped if the scheduler has fallen this far behind
+fn queue_action(action: KeyAction) {
+    let _ = ACTIONS.try_send(action);
 }
 
-fn handle_vibe_key(key: usize, delay: &mut cortex_m::delay::Delay) {
-    match key {
-        0 => {
-            // REC: Cmd+Shift+R
-            send_keys(&[Keyboard::LeftGUI, Keyboard::LeftShift, Keyboard::R]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        1 => {
-            // STOP: Escape
-            send_keys(&[Keyboard::Escape]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        2 => {
-            // CYCLE: Shift+Tab
-            send_keys(&[Keyboard::LeftShift, Keyboard::Tab]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        3 => {
-            // ESC: Escape x2
-            send_keys(&[Keyboard::Escape]);
-            delay.delay_ms(50_u32);
-            release_keys();
-            delay.delay_ms(50_u32);
-            send_keys(&[Keyboard::Escape]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        4 => {
-            // ENTER
-            send_keys(&[Keyboard::ReturnEnter]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        5 => {
-            // TAB
-            send_keys(&[Keyboard::Tab]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        6 => {
-            // UP: Up Arrow
-            send_keys(&[Keyboard::UpArrow]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        7 => {
-            // DOWN: Down Arrow
-            send_keys(&[Keyboard::DownArrow]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        8 => {
-            // SAVE: Cmd+S
-            send_keys(&[Keyboard::LeftGUI, Keyboard::S]);
-            delay.delay_ms(50_u32);
-            release_keys();
+// Turns pin samples into serial events and key actions
+#[embassy_executor::task]
+async fn input_task(pins: InputPins, state: &'static SharedState) {
+    let mut debouncer = Debouncer::new(DEBOUNCE_MODE, DEBOUNCE_MS);
+    let mut holds = HoldTracker::new(HOLD_TIMING);
+    let mut button_debouncer = Debouncer::new(DEBOUNCE_MODE, DEBOUNCE_MS);
+    let mut button_gestures = GestureDetector::new();
+    let mut quadrature = Quadrature::new(pins.encoder_a.is_low(), pins.encoder_b.is_low());
+    let mut ticker = Ticker::every(INPUT_POLL);
+
+    loop {
+        ticker.next().await;
+        let now_ms = now_ms();
+
+        // Keys
+        let keys: [bool; NUM_KEYS] = core::array::from_fn(|key| pins.keys[key].is_low());
+        for event in debouncer.update(now_ms, keys) {
+            let action = with_state(state, |s| {
+                send_key_event(s, &mut PipeOut, event.key, event.pressed);
+                s.layer.action(event.key)
+            });
+            let action = if event.pressed {
+                holds.press(event.key, action, now_ms)
+            } else {
+                holds.release(event.key)
+            };
+            if let Some(action) = action {
+                queue_action(action);
+            }
         }
-        9 => {
-            // COPY: Cmd+C
-            send_keys(&[Keyboard::LeftGUI, Keyboard::C]);
-            delay.delay_ms(50_u32);
-            release_keys();
+        for action in holds.poll(now_ms) {
+            queue_action(action);
         }
-        10 => {
-            // PASTE: Cmd+V
-            send_keys(&[Keyboard::LeftGUI, Keyboard::V]);
-            delay.delay_ms(50_u32);
-            release_keys();
+
+        // Encoder rotation
+        let step = quadrature.update(pins.encoder_a.is_low(), pins.encoder_b.is_low());
+        if step != 0 {
+            let delta: &[u8] = if step > 0 { b"+1" } else { b"-1" };
+            let action = with_state(state, |s| {
+                send_event(s, &mut PipeOut, &[b"EVT:ENC:", delta]);
+                s.rotation(step)
+            });
+            queue_action(action);
+        }
+
+        // Encoder button gestures
+        let button = [pins.encoder_btn.is_low()];
+        let edge = button_debouncer.update(now_ms, button).first().map(|e| e.pressed);
+        if let Some(gesture) = button_gestures.update(now_ms, edge) {
+            let action = with_state(state, |s| {
+                send_event(s, &mut PipeOut, &[b"EVT:BTN:", gesture.name().as_bytes()]);
+                s.button_action(gesture)
+            });
+            if let Some(action) = action {
+                queue_action(action);
+            }
         }
-        _ => {}
     }
 }
 
-fn handle_media_key(key: usize, delay: &mut cortex_m::delay::Delay) {
-    // Media keys use consumer control codes, but we'll use keyboard shortcuts
-    match key {
-        0 => {
-            // PREV: F7 (common media key)
-            send_keys(&[Keyboard::F7]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        1 => {
-            // PLAY: F8
-            send_keys(&[Keyboard::F8]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        2 => {
-            // NEXT: F9
-            send_keys(&[Keyboard::F9]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        3 => {
-            // MUTE: F10
-            send_keys(&[Keyboard::F10]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        4 => {
-            // VOL-: F11
-            send_keys(&[Keyboard::F11]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        5 => {
-            // VOL+: F12
-            send_keys(&[Keyboard::F12]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        6 => {
-            // RWD: Cmd+Left
-            send_keys(&[Keyboard::LeftGUI, Keyboard::LeftArrow]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        7 => {
-            // STOP: Escape
-            send_keys(&[Keyboard::Escape]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        8 => {
-            // FWD: Cmd+Right
-            send_keys(&[Keyboard::LeftGUI, Keyboard::RightArrow]);
-            delay.delay_ms(50_u32);
-            release_keys();
-        }
-        9 => {
-            // MIC: Cmd+Shift+M (common mute mic)
-            send_keys(&[Keyboard::LeftGUI, Keyboard::LeftShift, Keyboard::M]);
-            delay.delay_ms(50_u32);
-            release_keys();
+// Runs queued actions and paces their HID reports
+#[embassy_executor::task]
+async fn action_task(state: &'static SharedState) {
+    let mut scheduler = Scheduler::new();
+    loop {
+        // Nothing to send: sleep until the next action arrives
+        if scheduler.is_idle() {
+            let action = ACTIONS.receive().await;
+            with_state(state, |s| scheduler.run_action(action, s, &mut PipeOut));
         }
-        10 => {
-            // CAM: Cmd+Shift+V (common toggle cam)
-            send_keys(&[Keyboard::LeftGUI, Keyboard::LeftShift, Keyboard::V]);
-            delay.delay_ms(50_u32);
-            release_keys();
+        while let Ok(action) = ACTIONS.try_receive() {
+            with_state(state, |s| scheduler.run_action(action, s, &mut PipeOut));
         }
-        _ => {}
-    }
-}
 
-fn handle_snippet_key(key: usize, delay: &mut cortex_m::delay::Delay) {
-    // Type snippet text
-    let snippets = [
-        "!td", "!sh", ":rocket:", "snip04", "snip05",
-        "snip06", "snip07", "snip08", "snip09", "snip10", "snip11",
-    ];
-    if key < 11 {
-        send_string(snippets[key], delay);
+        with_state(state, |s| send_layer_event(s, &mut PipeOut));
+
+        // Send the next queued HID report once it's due
+        if let Some(report) = scheduler.poll(now_ms()) {
+            REPORTS.send(report).await;
+        }
+        Timer::after_millis(1).await;
     }
 }
 
 // =============================================================================
-// Main Entry
+// Display and LEDs
 // =============================================================================
 
-#[entry]
-fn main() -> ! {
-    let mut pac = pac::Peripherals::take().unwrap();
-    let core = pac::CorePeripherals::take().unwrap();
-    let mut watchdog = Watchdog::new(pac.WATCHDOG);
-    let sio = Sio::new(pac.SIO);
-
-    let clocks = init_clocks_and_plls(
-        XOSC_CRYSTAL_FREQ,
-        pac.XOSC,
-        pac.CLOCKS,
-        pac.PLL_SYS,
-        pac.PLL_USB,
-        &mut pac.RESETS,
-        &mut watchdog,
-    )
-    .ok()
-    .unwrap();
-
-    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
-    let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
-
-    // Start watchdog - will reset device if not fed for 5 seconds
-    // We only feed when USB is configured, so suspend triggers reset
-    watchdog.start(fugit::MicrosDurationU32::millis(5000));
-
-    // USB Setup
-    static mut USB_BUS: Option<UsbBusAllocator<UsbBus>> = None;
-    unsafe {
-        USB_BUS = Some(UsbBusAllocator::new(UsbBus::new(
-            pac.USBCTRL_REGS,
-            pac.USBCTRL_DPRAM,
-            clocks.usb_clock,
-            true,
-            &mut pac.RESETS,
-        )));
-    }
-    let usb_bus = unsafe { USB_BUS.as_ref().unwrap() };
-
-    let usb_hid = UsbHidClassBuilder::new()
-        .add_device(NKROBootKeyboardConfig::default())
-        .build(usb_bus);
-
-    let usb_serial = SerialPort::new(usb_bus);
-
-    let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x239A, 0x8107))
-        .strings(&[StringDescriptors::default()
-            .manufacturer("VibePad")
-            .product("Vibe Pad")
-            .serial_number("VIBE001")])
-        .unwrap()
-        .composite_with_iads()
-        .build();
-
-    critical_section::with(|cs| {
-        USB_HID.borrow_ref_mut(cs).replace(usb_hid);
-        USB_SERIAL.borrow_ref_mut(cs).replace(usb_serial);
-        USB_DEVICE.borrow_ref_mut(cs).replace(usb_dev);
-    });
-
-    unsafe {
-        pac::NVIC::unmask(pac::Interrupt::USBCTRL_IRQ);
-    }
-
-    // GPIO Setup
-    let pins = Pins::new(
-        pac.IO_BANK0,
-        pac.PADS_BANK0,
-        sio.gpio_bank0,
-        &mut pac.RESETS,
-    );
-
-    // OLED SPI
-    let sclk = pins.gpio26.into_function::<FunctionSpi>();
-    let mosi = pins.gpio27.into_function::<FunctionSpi>();
-    let miso = pins.gpio28.into_function::<FunctionSpi>();
-    let oled_cs = pins.gpio22.into_push_pull_output_in_state(rp2040_hal::gpio::PinState::High);
-    let oled_dc = pins.gpio24.into_push_pull_output();
-    let mut oled_reset = pins.gpio23.into_push_pull_output_in_state(rp2040_hal::gpio::PinState::High);
-
-    let spi = Spi::<_, _, _, 8>::new(pac.SPI1, (mosi, miso, sclk));
-    let spi = spi.init(
-        &mut pac.RESETS,
-        clocks.peripheral_clock.freq(),
-        fugit::RateExtU32::MHz(10),
-        embedded_hal::spi::MODE_0,
-    );
-
-    oled_reset.set_low().ok();
-    delay.delay_ms(10_u32);
-    oled_reset.set_high().ok();
-    delay.delay_ms(10_u32);
-
-    let mut display: GraphicsMode<_> = Builder::new().connect_spi(spi, oled_dc, oled_cs).into();
-    display.init().ok();
-    display.flush().ok();
-
-    // NeoPixels
-    let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
-    let mut ws = Ws2812::new(
-        pins.gpio19.into_function(),
-        &mut pio,
-        sm0,
-        clocks.peripheral_clock.freq(),
-        timer.count_down(),
-    );
-
-    // Speaker (startup beep)
-    let _speaker_shutdown = pins.gpio14.into_push_pull_output_in_state(rp2040_hal::gpio::PinState::High);
-    let pwm_slices = Slices::new(pac.PWM, &mut pac.RESETS);
-    let mut pwm = pwm_slices.pwm0;
-    pwm.set_ph_correct();
-    pwm.enable();
-    pwm.channel_a.output_to(pins.gpio16);
-
-    let sys_freq = clocks.system_clock.freq().to_Hz();
-    let effective_freq = sys_freq / 64;
-    let top = (effective_freq / 440) as u16;
-    pwm.set_div_int(64);
-    pwm.set_top(top);
-    pwm.channel_a.set_duty(top / 8);
-    delay.delay_ms(80_u32);
-    pwm.channel_a.set_duty(0);
-
-    // Encoder
-    let encoder_a = pins.gpio18.into_pull_up_input();
-    let encoder_b = pins.gpio17.into_pull_up_input();
-    let _encoder_btn = pins.gpio0.into_pull_up_input();
-    let mut last_a = encoder_a.is_low().unwrap_or(false);
-
-    // Keys
-    let key1 = pins.gpio1.into_pull_up_input();
-    let key2 = pins.gpio2.into_pull_up_input();
-    let key3 = pins.gpio3.into_pull_up_input();
-    let key4 = pins.gpio4.into_pull_up_input();
-    let key5 = pins.gpio5.into_pull_up_input();
-    let key6 = pins.gpio6.into_pull_up_input();
-    let key7 = pins.gpio7.into_pull_up_input();
-    let key8 = pins.gpio8.into_pull_up_input();
-    let key9 = pins.gpio9.into_pull_up_input();
-    let key10 = pins.gpio10.into_pull_up_input();
-    let key11 = pins.gpio11.into_pull_up_input();
-    let key12 = pins.gpio12.into_pull_up_input();
-
-    // State
-    let mut state = State::new();
-    let mut prev_keys: [bool; 12] = [false; 12];
-    let mut tick_counter: u32 = 0;
-    let mut serial_buf: [u8; 64] = [0; 64];
-    let mut serial_pos: usize = 0;
+type Display =
+    GraphicsMode<SpiInterface<Spi<'static, SPI1, spi::Blocking>, Output<'static>, Output<'static>>>;
 
+// Draws into the frame buffer while holding the state, then flushes over
+// SPI after letting go of it
+#[embassy_executor::task]
+async fn display_task(mut display: Display, state: &'static SharedState) {
     let text_style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
-
     loop {
-        tick_counter = tick_counter.wrapping_add(1);
-
-        // USB tick
-        if tick_counter % 10 == 0 {
-            tick_usb();
-        }
+        Timer::after(DISPLAY_POLL).await;
+        let redraw = with_state(state, |s| {
+            if !s.display_dirty {
+                return false;
+            }
+            s.display_dirty = false;
+            display.clear();
 
-        // Read serial data
-        let mut temp_buf = [0u8; 32];
-        let count = read_serial(&mut temp_buf);
-        for i in 0..count {
-            let c = temp_buf[i];
-            if c == b'\n' || c == b'\r' {
-                if serial_pos > 0 {
-                    process_command(&serial_buf[..serial_pos], &mut state);
-                    serial_pos = 0;
+            // The settings menu takes the whole screen, one item per row
+            if let Some(open) = s.menu {
+                for index in 0..MENU_ITEMS.len() {
+                    let y = 10 + 12 * index as i32;
+                    Text::new(&menu::line(s, &open, index), Point::new(0, y), text_style)
+                        .draw(&mut display)
+                        .ok();
                 }
-            } else if serial_pos < serial_buf.len() {
-                serial_buf[serial_pos] = c;
-                serial_pos += 1;
+                return true;
             }
-        }
 
-        // Encoder rotation
-        let a = encoder_a.is_low().unwrap_or(false);
-        let b = encoder_b.is_low().unwrap_or(false);
-        if a != last_a && a {
-            if state.layer != Layer::Snippet {
-                let new_layer = state.layer.next();
-                state.set_layer(new_layer);
+            // Key labels in a 3x4 grid, rows at y=10/22/34/46
+            for key in 0..NUM_KEYS {
+                let x = 5 + 42 * (key % 3) as i32;
+                let y = 10 + 12 * (key / 3) as i32;
+                Text::new(s.label(key), Point::new(x, y), text_style)
+                    .draw(&mut display)
+                    .ok();
             }
-        }
-        last_a = a;
-        let _ = b; // silence unused warning
-
-        // Update display
-        if state.display_dirty {
-            display.clear();
-
-            // Get labels for current layer
-            let labels = state.layer.default_labels();
-
-            // Row 1: keys 1-3 (y=10)
-            Text::new(labels[0], Point::new(5, 10), text_style).draw(&mut display).ok();
-            Text::new(labels[1], Point::new(47, 10), text_style).draw(&mut display).ok();
-            Text::new(labels[2], Point::new(89, 10), text_style).draw(&mut display).ok();
-
-            // Row 2: keys 4-6 (y=22)
-            Text::new(labels[3], Point::new(5, 22), text_style).draw(&mut display).ok();
-            Text::new(labels[4], Point::new(47, 22), text_style).draw(&mut display).ok();
-            Text::new(labels[5], Point::new(89, 22), text_style).draw(&mut display).ok();
-
-            // Row 3: keys 7-9 (y=34)
-            Text::new(labels[6], Point::new(5, 34), text_style).draw(&mut display).ok();
-            Text::new(labels[7], Point::new(47, 34), text_style).draw(&mut display).ok();
-            Text::new(labels[8], Point::new(89, 34), text_style).draw(&mut display).ok();
-
-            // Row 4: keys 10-12 (y=46)
-            Text::new(labels[9], Point::new(5, 46), text_style).draw(&mut display).ok();
-            Text::new(labels[10], Point::new(47, 46), text_style).draw(&mut display).ok();
-            Text::new(labels[11], Point::new(89, 46), text_style).draw(&mut display).ok();
 
             // Bottom: Layer name + status OR Claude message (y=60)
-            if !state.message.is_empty() {
-                Text::new(state.message.as_str(), Point::new(5, 60), text_style)
+            if !s.settings.message.is_empty() {
+                Text::new(s.settings.message.as_str(), Point::new(5, 60), text_style)
                     .draw(&mut display)
                     .ok();
             } else {
-                let layer_name = state.layer.name();
-                Text::new(layer_name, Point::new(5, 60), text_style)
+                Text::new(s.layer.name(), Point::new(5, 60), text_style)
                     .draw(&mut display)
                     .ok();
+                if s.recording.is_some() {
+                    Text::new("REC", Point::new(90, 60), text_style)
+                        .draw(&mut display)
+                        .ok();
+                }
                 let mut icon_buf = [0u8; 4];
-                let icon_str = state.status.icon().encode_utf8(&mut icon_buf);
+                let icon_str = s.status.icon().encode_utf8(&mut icon_buf);
                 Text::new(icon_str, Point::new(120, 60), text_style)
                     .draw(&mut display)
                     .ok();
             }
-
+            true
+        });
+        if redraw {
             display.flush().ok();
-            state.display_dirty = false;
         }
+    }
+}
 
-        // Read keys
-        let keys = [
-            key1.is_low().unwrap_or(false),
-            key2.is_low().unwrap_or(false),
-            key3.is_low().unwrap_or(false),
-            key4.is_low().unwrap_or(false),
-            key5.is_low().unwrap_or(false),
-            key6.is_low().unwrap_or(false),
-            key7.is_low().unwrap_or(false),
-            key8.is_low().unwrap_or(false),
-            key9.is_low().unwrap_or(false),
-            key10.is_low().unwrap_or(false),
-            key11.is_low().unwrap_or(false),
-            key12.is_low().unwrap_or(false),
-        ];
-
-        // Process key presses
-        for (i, (&pressed, &prev)) in keys.iter().zip(prev_keys.iter()).enumerate() {
-            if pressed && !prev {
-                // Key 12 is always snippet toggle
-                if i == 11 {
-                    state.toggle_snippet();
-                } else {
-                    match state.layer {
-                        Layer::Vibe => handle_vibe_key(i, &mut delay),
-                        Layer::Media => handle_media_key(i, &mut delay),
-                        Layer::Snippet => handle_snippet_key(i, &mut delay),
-                    }
-                }
+#[embassy_executor::task]
+async fn led_task(mut ws: PioWs2812<'static, PIO0, 0, NUM_LEDS, Grb>, state: &'static SharedState) {
+    let mut ticker = Ticker::every(LED_FRAME);
+    let mut tick: u32 = 0;
+    loop {
+        ticker.next().await;
+        tick = tick.wrapping_add(1);
+        let mut frame = [RGB8::default(); NUM_LEDS];
+        with_state(state, |s| {
+            let leds = compute_leds(s, tick);
+            let dimmed = brightness(leds.iter().copied(), s.settings.brightness);
+            for (out, color) in frame.iter_mut().zip(dimmed) {
+                *out = color;
             }
-        }
-        prev_keys = keys;
+        });
+        ws.write(&frame).await;
+    }
+}
 
-        // Check USB state - feed watchdog only when configured
-        // If suspended too long (5s), watchdog resets device
-        let usb_state = poll_usb();
-        if matches!(usb_state, Some(UsbDeviceState::Configured)) {
-            watchdog.feed();
-        }
+// =============================================================================
+// Main Entry
+// =============================================================================
 
-        // Update LEDs
-        let leds = compute_leds(&state, tick_counter);
-        ws.write(brightness(leds.iter().copied(), BRIGHTNESS_LEVEL))
-            .unwrap();
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
 
-        delay.delay_ms(10_u32);
-    }
-}
+    // State
+    let (settings_store, settings) = SettingsStore::load(Flash::new_blocking(p.FLASH));
+    static STATE: StaticCell<SharedState> = StaticCell::new();
+    let state: &'static SharedState = STATE.init(Mutex::new(RefCell::new(State::new(settings))));
 
-#[allow(non_snake_case)]
-#[interrupt]
-unsafe fn USBCTRL_IRQ() {
-    critical_section::with(|cs| {
-        if let Some(usb_dev) = USB_DEVICE.borrow_ref_mut(cs).as_mut() {
-            let mut hid = USB_HID.borrow_ref_mut(cs);
-            let mut serial = USB_SERIAL.borrow_ref_mut(cs);
-            if let (Some(hid), Some(serial)) = (hid.as_mut(), serial.as_mut()) {
-                usb_dev.poll(&mut [hid, serial]);
-            }
-        }
-    });
+    // USB Setup
+    let mut config = embassy_usb::Config::new(0x239A, 0x8107);
+    config.manufacturer = Some("VibePad");
+    config.product = Some("Vibe Pad");
+    config.serial_number = Some("VIBE001");
+    config.composite_with_iads = true;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 64]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static USB_HANDLER: StaticCell<UsbStateHandler> = StaticCell::new();
+    let mut builder = Builder::new(
+        Driver::new(p.USB, Irqs),
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 64]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+    builder.handler(USB_HANDLER.init(UsbStateHandler));
+
+    // The keyboard is created first so it becomes interface 0
+    static KEYBOARD_STATE: StaticCell<hid::State> = StaticCell::new();
+    let keyboard = HidWriter::new(
+        &mut builder,
+        KEYBOARD_STATE.init(hid::State::new()),
+        hid::Config {
+            report_descriptor: NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 1,
+            max_packet_size: 32,
+            hid_subclass: HidSubclass::Boot,
+            hid_boot_protocol: HidBootProtocol::Keyboard,
+        },
+    );
+    static CONSUMER_STATE: StaticCell<hid::State> = StaticCell::new();
+    let consumer = HidWriter::new(
+        &mut builder,
+        CONSUMER_STATE.init(hid::State::new()),
+        hid::Config {
+            report_descriptor: MULTIPLE_CODE_REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: 8,
+            hid_subclass: HidSubclass::No,
+            hid_boot_protocol: HidBootProtocol::None,
+        },
+    );
+    static MOUSE_STATE: StaticCell<hid::State> = StaticCell::new();
+    let mouse = HidWriter::new(
+        &mut builder,
+        MOUSE_STATE.init(hid::State::new()),
+        hid::Config {
+            report_descriptor: WHEEL_MOUSE_REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: 8,
+            hid_subclass: HidSubclass::Boot,
+            hid_boot_protocol: HidBootProtocol::Mouse,
+        },
+    );
+
+    static SERIAL_STATE: StaticCell<cdc_acm::State> = StaticCell::new();
+    let serial = CdcAcmClass::new(&mut builder, SERIAL_STATE.init(cdc_acm::State::new()), 64);
+    let (serial_tx, serial_rx) = serial.split();
+
+    spawner.spawn(usb_task(builder.build()).unwrap());
+    spawner.spawn(hid_task(keyboard, consumer, mouse).unwrap());
+    spawner.spawn(serial_rx_task(serial_rx, state).unwrap());
+    spawner.spawn(serial_tx_task(serial_tx).unwrap());
+    spawner.spawn(watchdog_task(Watchdog::new(p.WATCHDOG)).unwrap());
+
+    // OLED SPI
+    let mut spi_config = spi::Config::default();
+    spi_config.frequency = 10_000_000;
+    let spi = Spi::new_blocking(p.SPI1, p.PIN_26, p.PIN_27, p.PIN_28, spi_config);
+    let oled_cs = Output::new(p.PIN_22, Level::High);
+    let oled_dc = Output::new(p.PIN_24, Level::Low);
+    let mut oled_reset = Output::new(p.PIN_23, Level::High);
+
+    oled_reset.set_low();
+    Timer::after_millis(10).await;
+    oled_reset.set_high();
+    Timer::after_millis(10).await;
+
+    let mut display: Display = DisplayBuilder::new().connect_spi(spi, oled_dc, oled_cs).into();
+    display.init().ok();
+    display.flush().ok();
+    spawner.spawn(display_task(display, state).unwrap());
+
+    // NeoPixels
+    let Pio { mut common, sm0, .. } = Pio::new(p.PIO0, Irqs);
+    let program = PioWs2812Program::new(&mut common);
+    let ws = PioWs2812::new(&mut common, sm0, p.DMA_CH0, Irqs, p.PIN_19, &program);
+    spawner.spawn(led_task(ws, state).unwrap());
+
+    // Keys and encoder
+    let pins = InputPins {
+        keys: [
+            Input::new(p.PIN_1, Pull::Up),
+            Input::new(p.PIN_2, Pull::Up),
+            Input::new(p.PIN_3, Pull::Up),
+            Input::new(p.PIN_4, Pull::Up),
+            Input::new(p.PIN_5, Pull::Up),
+            Input::new(p.PIN_6, Pull::Up),
+            Input::new(p.PIN_7, Pull::Up),
+            Input::new(p.PIN_8, Pull::Up),
+            Input::new(p.PIN_9, Pull::Up),
+            Input::new(p.PIN_10, Pull::Up),
+            Input::new(p.PIN_11, Pull::Up),
+            Input::new(p.PIN_12, Pull::Up),
+        ],
+        encoder_a: Input::new(p.PIN_18, Pull::Up),
+        encoder_b: Input::new(p.PIN_17, Pull::Up),
+        encoder_btn: Input::new(p.PIN_0, Pull::Up),
+    };
+    spawner.spawn(input_task(pins, state).unwrap());
+    spawner.spawn(action_task(state).unwrap());
+    spawner.spawn(storage_task(settings_store, state).unwrap());
+
+    // Speaker (startup beep)
+    let _speaker_shutdown = Output::new(p.PIN_14, Level::High);
+    let top = (clocks::clk_sys_freq() / 64 / 440) as u16;
+    let mut beep = pwm::Config::default();
+    beep.phase_correct = true;
+    beep.divider = 64.into();
+    beep.top = top;
+    beep.compare_a = top / 8;
+    let mut speaker = Pwm::new_output_a(p.PWM_SLICE0, p.PIN_16, beep.clone());
+    Timer::after_millis(80).await;
+    beep.compare_a = 0;
+    speaker.set_config(&beep);
+
+    // Keeps the speaker pins alive; everything else runs in the tasks
+    core::future::pending::<()>().await
 }